@@ -0,0 +1,603 @@
+//! the resize pipeline: convert once into a [`Frame`], resize to each size and encode
+
+use std::{cell::RefCell, io::BufWriter};
+
+use bytes::Bytes;
+use fast_image_resize::{images::Image, IntoImageView, MulDiv, ResizeOptions, Resizer};
+
+use image::{
+    codecs::{avif, jpeg, png, webp},
+    ColorType, DynamicImage, ImageEncoder, ImageFormat,
+};
+use rayon::iter::{
+    IndexedParallelIterator, IntoParallelIterator, IntoParallelRefIterator, ParallelIterator,
+};
+
+use crate::{Error, Result};
+
+/// ravif speed 1-10, slower is smaller, 8 keeps a request within a few seconds
+const AVIF_SPEED: u8 = 8;
+
+const AVIF_QUALITY: u8 = 70;
+
+/// a downscale may start from another output when that output is at least this many times larger
+const CASCADE_MIN_RATIO: u32 = 2;
+
+thread_local! {
+    // Resizer keeps its internal buffers between calls, one per pool thread
+    static RESIZER: RefCell<Resizer> = RefCell::new(Resizer::new());
+}
+
+/// pixels ready for resizing, the source is converted and premultiplied once per request
+pub struct Frame {
+    image: Image<'static>,
+    color: ColorType,
+    premultiplied: bool,
+}
+
+impl Frame {
+    /// converts and premultiplies `src_image`, the colors and alpha of the outputs follow it
+    pub fn from_image(src_image: &DynamicImage) -> Result<Frame> {
+        let converted;
+        let src_image = match Frame::color_for(src_image.color()) {
+            color if color == src_image.color() => src_image,
+            ColorType::Rgba8 => {
+                converted = DynamicImage::ImageRgba8(src_image.to_rgba8());
+                &converted
+            }
+            _ => {
+                converted = DynamicImage::ImageRgb8(src_image.to_rgb8());
+                &converted
+            }
+        };
+
+        let pixel_type = src_image.pixel_type().unwrap();
+        let mul_div = MulDiv::default();
+
+        let (image, premultiplied) = if mul_div.is_supported(pixel_type) {
+            let mut image = Image::new(src_image.width(), src_image.height(), pixel_type);
+            mul_div.multiply_alpha(src_image, &mut image)?;
+            (image, true)
+        } else {
+            let image = Image::from_vec_u8(
+                src_image.width(),
+                src_image.height(),
+                src_image.as_bytes().to_vec(),
+                pixel_type,
+            )?;
+            (image, false)
+        };
+
+        Ok(Frame {
+            image,
+            color: src_image.color(),
+            premultiplied,
+        })
+    }
+
+    /// color of the frame made from a `color` image, 8 and 16 bit images are kept as they
+    /// are, f32 ones are not supported by fast_image_resize and become 8 bit
+    pub fn color_for(color: ColorType) -> ColorType {
+        match color {
+            ColorType::L8
+            | ColorType::La8
+            | ColorType::Rgb8
+            | ColorType::Rgba8
+            | ColorType::L16
+            | ColorType::La16
+            | ColorType::Rgb16
+            | ColorType::Rgba16 => color,
+            _ if color.has_alpha() => ColorType::Rgba8,
+            _ => ColorType::Rgb8,
+        }
+    }
+
+    /// in pixels
+    pub fn width(&self) -> u32 {
+        self.image.width()
+    }
+
+    /// in pixels
+    pub fn height(&self) -> u32 {
+        self.image.height()
+    }
+
+    /// the whole frame to `width`x`height`, aspect ratio is up to the caller
+    pub fn resize(&self, width: u32, height: u32) -> Result<Frame> {
+        self.resize_cropped(width, height, None)
+    }
+
+    /// resize only the `crop` part of the frame, see `fit_size`
+    pub fn resize_cropped(&self, width: u32, height: u32, crop: Option<Crop>) -> Result<Frame> {
+        let mut dst_image = Image::new(width, height, self.image.pixel_type());
+
+        // alpha is already multiplied, the resizer must not do it again
+        let mut options = ResizeOptions::new().use_alpha(false);
+        if let Some(crop) = crop {
+            options = options.crop(crop.left, crop.top, crop.width, crop.height);
+        }
+        RESIZER.with_borrow_mut(|resizer| resizer.resize(&self.image, &mut dst_image, &options))?;
+
+        Ok(Frame {
+            image: dst_image,
+            color: self.color,
+            premultiplied: self.premultiplied,
+        })
+    }
+
+    /// `quality` 1-100 applies to jpeg and avif, png and webp are lossless
+    pub fn encode_with_quality(
+        &self,
+        target_type: ImageFormat,
+        quality: Option<u8>,
+    ) -> Result<Bytes> {
+        let unmultiplied;
+        let image = if self.premultiplied {
+            let mut image = self.image.copy();
+            MulDiv::default().divide_alpha_inplace(&mut image)?;
+            unmultiplied = image;
+            &unmultiplied
+        } else {
+            &self.image
+        };
+
+        let (width, height) = (image.width(), image.height());
+        let color = encodable_color(self.color, target_type);
+        let converted;
+        let buffer = if color == self.color {
+            image.buffer()
+        } else {
+            converted = convert_color(image.buffer(), self.color, color);
+            &converted
+        };
+        let color = color.into();
+
+        let mut writer = BufWriter::new(Vec::new());
+        match target_type {
+            ImageFormat::Png => {
+                png::PngEncoder::new(&mut writer).write_image(buffer, width, height, color)?;
+            }
+            ImageFormat::Jpeg => {
+                let encoder = match quality {
+                    Some(quality) => jpeg::JpegEncoder::new_with_quality(&mut writer, quality),
+                    None => jpeg::JpegEncoder::new(&mut writer),
+                };
+                encoder.write_image(buffer, width, height, color)?;
+            }
+            ImageFormat::WebP => {
+                webp::WebPEncoder::new_lossless(&mut writer)
+                    .write_image(buffer, width, height, color)?;
+            }
+            ImageFormat::Avif => {
+                avif::AvifEncoder::new_with_speed_quality(
+                    &mut writer,
+                    AVIF_SPEED,
+                    quality.unwrap_or(AVIF_QUALITY),
+                )
+                .write_image(buffer, width, height, color)?;
+            }
+            _ => return Err(Error::UnsupportedFormat(target_type)),
+        };
+
+        let bs = Bytes::from(writer.into_inner()?);
+
+        Ok(bs)
+    }
+}
+
+/// closest color `target_type` can encode a `color` frame in, jpeg has no alpha and
+/// only png keeps 16 bits
+fn encodable_color(color: ColorType, target_type: ImageFormat) -> ColorType {
+    let eight_bit = match color {
+        ColorType::L16 => ColorType::L8,
+        ColorType::La16 => ColorType::La8,
+        ColorType::Rgb16 => ColorType::Rgb8,
+        ColorType::Rgba16 => ColorType::Rgba8,
+        color => color,
+    };
+
+    match target_type {
+        ImageFormat::Png => color,
+        ImageFormat::Jpeg => match eight_bit {
+            ColorType::La8 => ColorType::L8,
+            ColorType::Rgba8 => ColorType::Rgb8,
+            color => color,
+        },
+        _ => eight_bit,
+    }
+}
+
+/// `from` pixels as `to`, which is 8 bit with the same channels or without the trailing
+/// alpha, 16 bit samples keep their high byte
+fn convert_color(buffer: &[u8], from: ColorType, to: ColorType) -> Vec<u8> {
+    let channels = from.channel_count() as usize;
+    let sample = from.bytes_per_pixel() as usize / channels;
+    let kept = to.channel_count() as usize;
+
+    let mut out = Vec::with_capacity(buffer.len() / channels / sample * kept);
+    for pixel in buffer.chunks_exact(channels * sample) {
+        for s in pixel.chunks_exact(sample).take(kept) {
+            out.push(match *s {
+                [hi, lo] => (u16::from_ne_bytes([hi, lo]) >> 8) as u8,
+                _ => s[0],
+            });
+        }
+    }
+    out
+}
+
+/// how an image is fitted into a `w`x`h` box
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Fit {
+    /// inside the box, aspect ratio kept
+    Contain,
+    /// fills the box, the overflow is cropped evenly from both sides
+    Cover,
+    /// exactly the box, aspect ratio ignored
+    Fill,
+}
+
+impl Fit {
+    /// `contain`, `cover` or `fill`
+    pub fn from_name(name: &str) -> Option<Fit> {
+        match name {
+            "contain" => Some(Fit::Contain),
+            "cover" => Some(Fit::Cover),
+            "fill" => Some(Fit::Fill),
+            _ => None,
+        }
+    }
+}
+
+/// part of the source to resize, in source pixels
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Crop {
+    /// from the left edge
+    pub left: f64,
+    /// from the top edge
+    pub top: f64,
+    /// of the crop box
+    pub width: f64,
+    /// of the crop box
+    pub height: f64,
+}
+
+/// output size and source crop for a box, a missing side follows the aspect ratio
+pub fn fit_size(
+    source: (u32, u32),
+    width: Option<u32>,
+    height: Option<u32>,
+    fit: Fit,
+) -> ((u32, u32), Option<Crop>) {
+    let (sw, sh) = (source.0 as f64, source.1 as f64);
+
+    let (w, h) = match (width, height) {
+        (None, None) => return (source, None),
+        (Some(w), None) => return ((w, (sh * w as f64 / sw).round().max(1.0) as u32), None),
+        (None, Some(h)) => return (((sw * h as f64 / sh).round().max(1.0) as u32, h), None),
+        (Some(w), Some(h)) => (w, h),
+    };
+
+    match fit {
+        Fit::Fill => ((w, h), None),
+        Fit::Contain => {
+            let scale = (w as f64 / sw).min(h as f64 / sh);
+            (
+                (
+                    (sw * scale).round().max(1.0) as u32,
+                    (sh * scale).round().max(1.0) as u32,
+                ),
+                None,
+            )
+        }
+        Fit::Cover => {
+            let scale = (w as f64 / sw).max(h as f64 / sh);
+            let (cw, ch) = (w as f64 / scale, h as f64 / scale);
+            let crop = Crop {
+                left: (sw - cw) / 2.0,
+                top: (sh - ch) / 2.0,
+                width: cw,
+                height: ch,
+            };
+            ((w, h), Some(crop))
+        }
+    }
+}
+
+/// one output of [`resize_all`]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Target {
+    /// output width
+    pub width: u32,
+    /// output height
+    pub height: u32,
+    /// part of the source to resize, all of it when `None`
+    pub crop: Option<Crop>,
+}
+
+impl Target {
+    /// the source times `scale`, see [`target_size`]
+    pub fn scaled(source: (u32, u32), scale: f32) -> Target {
+        let (width, height) = target_size(source.0, source.1, scale);
+        Target {
+            width,
+            height,
+            crop: None,
+        }
+    }
+
+    /// the source fitted into a box, see [`fit_size`]
+    pub fn fit(source: (u32, u32), width: Option<u32>, height: Option<u32>, fit: Fit) -> Target {
+        let ((width, height), crop) = fit_size(source, width, height, fit);
+        Target {
+            width,
+            height,
+            crop,
+        }
+    }
+
+    /// `(width, height)`
+    pub fn size(&self) -> (u32, u32) {
+        (self.width, self.height)
+    }
+
+    /// all of the source at its aspect ratio, give or take rounding, so other outputs can
+    /// be derived from it
+    fn keeps_aspect(&self, source: (u32, u32)) -> bool {
+        let (sw, sh) = (source.0 as u64, source.1 as u64);
+        self.crop.is_none() && (self.width as u64 * sh).abs_diff(self.height as u64 * sw) <= sw + sh
+    }
+}
+
+/// `width`x`height` times `scale_factor`, rounded down and at least 1x1
+pub fn target_size(width: u32, height: u32, scale_factor: f32) -> (u32, u32) {
+    let target_width = (width as f32 * scale_factor) as u32;
+    let target_height = (height as f32 * scale_factor) as u32;
+
+    (target_width.max(1), target_height.max(1))
+}
+
+/// resize and encode every target on the current rayon pool, `on_done` gets each
+/// output with its target index as soon as it is encoded
+///
+/// with `cascade` a downscale starts from the smallest other output that is still
+/// CASCADE_MIN_RATIO times larger, instead of the full resolution source, cropped or
+/// stretched targets always start from the source
+pub fn resize_all<F>(
+    source: &Frame,
+    targets: &[Target],
+    target_type: ImageFormat,
+    quality: Option<u8>,
+    cascade: bool,
+    on_done: F,
+) -> Result<()>
+where
+    F: Fn(usize, Bytes) + Sync,
+{
+    if !cascade {
+        return targets.par_iter().enumerate().try_for_each(|(i, target)| {
+            on_done(
+                i,
+                source
+                    .resize_cropped(target.width, target.height, target.crop)?
+                    .encode_with_quality(target_type, quality)?,
+            );
+            Ok(())
+        });
+    }
+
+    let parents = cascade_plan((source.width(), source.height()), targets);
+
+    let mut frames: Vec<Option<Frame>> = targets.iter().map(|_| None).collect();
+    let mut finished = vec![false; targets.len()];
+
+    // every round resizes the targets whose parent is ready, in parallel
+    loop {
+        let ready: Vec<usize> = (0..targets.len())
+            .filter(|&i| !finished[i])
+            .filter(|&i| parents[i].is_none_or(|p| finished[p]))
+            .collect();
+
+        if ready.is_empty() {
+            break;
+        }
+
+        let done = ready
+            .into_par_iter()
+            .map(|i| {
+                let target = targets[i];
+                let frame = match parents[i] {
+                    Some(p) => frames[p]
+                        .as_ref()
+                        .unwrap()
+                        .resize(target.width, target.height)?,
+                    None => source.resize_cropped(target.width, target.height, target.crop)?,
+                };
+                on_done(i, frame.encode_with_quality(target_type, quality)?);
+                Ok((i, frame))
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        for (i, frame) in done {
+            if parents.contains(&Some(i)) {
+                frames[i] = Some(frame);
+            }
+            finished[i] = true;
+        }
+
+        // drop intermediates nobody is waiting on anymore
+        for (p, frame) in frames.iter_mut().enumerate() {
+            let waiting = (0..targets.len()).any(|i| parents[i] == Some(p) && !finished[i]);
+            if !waiting {
+                *frame = None;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// parent output of every target, `None` resizes from the source, see [`resize_all`]
+pub fn cascade_plan(source: (u32, u32), targets: &[Target]) -> Vec<Option<usize>> {
+    targets
+        .iter()
+        .map(|target| {
+            if !target.keeps_aspect(source) {
+                return None;
+            }
+            let (w, h) = target.size();
+
+            targets
+                .iter()
+                .enumerate()
+                .filter(|(_, parent)| parent.keeps_aspect(source))
+                // upscaled outputs carry no more detail than the source
+                .filter(|(_, parent)| parent.width < source.0 && parent.height < source.1)
+                .filter(|(_, parent)| {
+                    parent.width >= w.saturating_mul(CASCADE_MIN_RATIO)
+                        && parent.height >= h.saturating_mul(CASCADE_MIN_RATIO)
+                })
+                .min_by_key(|(_, parent)| parent.width as u64 * parent.height as u64)
+                .map(|(p, _)| p)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{ImageBuffer, Luma, LumaA, Rgb, Rgba};
+
+    use super::*;
+    use crate::format::ENCODE_FORMATS;
+
+    fn images() -> Vec<DynamicImage> {
+        let (w, h) = (8, 6);
+        vec![
+            DynamicImage::ImageLuma8(ImageBuffer::from_fn(w, h, |x, _| Luma([x as u8 * 30]))),
+            DynamicImage::ImageLumaA8(ImageBuffer::from_fn(w, h, |x, y| {
+                LumaA([x as u8 * 30, y as u8 * 40])
+            })),
+            DynamicImage::ImageRgb8(ImageBuffer::from_fn(w, h, |x, y| {
+                Rgb([x as u8 * 30, y as u8 * 40, 7])
+            })),
+            DynamicImage::ImageRgba8(ImageBuffer::from_fn(w, h, |x, y| {
+                Rgba([x as u8 * 30, y as u8 * 40, 7, 128])
+            })),
+            DynamicImage::ImageLuma16(ImageBuffer::from_fn(w, h, |x, _| Luma([x as u16 * 8000]))),
+            DynamicImage::ImageLumaA16(ImageBuffer::from_fn(w, h, |x, _| {
+                LumaA([x as u16 * 8000, 40000])
+            })),
+            DynamicImage::ImageRgb16(ImageBuffer::from_fn(w, h, |x, y| {
+                Rgb([x as u16 * 8000, y as u16 * 9000, 700])
+            })),
+            DynamicImage::ImageRgba16(ImageBuffer::from_fn(w, h, |x, y| {
+                Rgba([x as u16 * 8000, y as u16 * 9000, 700, 30000])
+            })),
+        ]
+    }
+
+    #[test]
+    fn every_frame_color_encodes_to_every_format() {
+        for image in images() {
+            let frame = Frame::from_image(&image).unwrap().resize(4, 3).unwrap();
+
+            for format in ENCODE_FORMATS {
+                let buf = frame
+                    .encode_with_quality(format, Some(80))
+                    .unwrap_or_else(|e| panic!("{:?} to {:?}: {}", image.color(), format, e));
+
+                // there is no avif decoder in this build
+                if format == ImageFormat::Avif {
+                    assert_eq!(&buf[4..8], b"ftyp");
+                    continue;
+                }
+
+                let decoded = image::load_from_memory_with_format(&buf, format).unwrap();
+                assert_eq!((decoded.width(), decoded.height()), (4, 3));
+                if format == ImageFormat::Png {
+                    assert_eq!(decoded.color(), image.color());
+                }
+                if format == ImageFormat::Jpeg {
+                    assert!(!decoded.color().has_alpha());
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn converting_keeps_the_high_byte_and_drops_alpha() {
+        let pixel = [0x1234u16, 0xabcd, 0xff00, 0x0102];
+        let buffer: Vec<u8> = pixel.iter().flat_map(|s| s.to_ne_bytes()).collect();
+
+        assert_eq!(
+            convert_color(&buffer, ColorType::Rgba16, ColorType::Rgb8),
+            [0x12, 0xab, 0xff]
+        );
+        assert_eq!(
+            convert_color(&buffer, ColorType::Rgba16, ColorType::Rgba8),
+            [0x12, 0xab, 0xff, 0x01]
+        );
+        assert_eq!(
+            convert_color(&[1, 2, 3, 4, 5, 6, 7, 8], ColorType::Rgba8, ColorType::Rgb8),
+            [1, 2, 3, 5, 6, 7]
+        );
+    }
+
+    fn scaled(source: (u32, u32), scales: &[f32]) -> Vec<Target> {
+        scales.iter().map(|s| Target::scaled(source, *s)).collect()
+    }
+
+    #[test]
+    fn cascades_from_the_smallest_parent_twice_as_large() {
+        let source = (1000, 800);
+        let targets = scaled(source, &[0.5, 0.25, 0.1, 0.3]);
+
+        // 0.3 is less than half of 0.5, 0.1 comes from 0.25 rather than 0.5
+        assert_eq!(
+            cascade_plan(source, &targets),
+            [None, Some(0), Some(1), None]
+        );
+    }
+
+    #[test]
+    fn upscaled_and_full_size_outputs_are_never_parents() {
+        let source = (1000, 800);
+        let targets = scaled(source, &[2.0, 1.0, 0.4]);
+
+        assert_eq!(cascade_plan(source, &targets), [None, None, None]);
+    }
+
+    #[test]
+    fn cropped_and_stretched_outputs_never_cascade() {
+        let source = (1000, 800);
+        let targets = vec![
+            Target::scaled(source, 0.5),
+            Target::fit(source, Some(100), Some(100), Fit::Cover),
+            Target::fit(source, Some(100), Some(100), Fit::Fill),
+            Target::fit(source, Some(100), None, Fit::Contain),
+        ];
+
+        assert_eq!(cascade_plan(source, &targets), [None, None, None, Some(0)]);
+    }
+
+    #[test]
+    fn a_parent_is_always_below_the_source_and_twice_the_target() {
+        let source = (1920, 1080);
+        let scales: Vec<f32> = (1..=40).map(|i| i as f32 / 20.0).collect();
+        let targets = scaled(source, &scales);
+
+        let plan = cascade_plan(source, &targets);
+        assert!(plan.iter().flatten().count() > 5);
+
+        for (i, parent) in plan.into_iter().enumerate() {
+            let Some(p) = parent else {
+                continue;
+            };
+            let (target, parent) = (&targets[i], &targets[p]);
+
+            assert!(parent.width < source.0 && parent.height < source.1);
+            assert!(parent.width >= target.width * CASCADE_MIN_RATIO);
+            assert!(parent.height >= target.height * CASCADE_MIN_RATIO);
+        }
+    }
+}
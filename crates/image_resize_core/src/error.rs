@@ -0,0 +1,93 @@
+use std::{
+    fmt::{self, Display},
+    io::{self, BufWriter, IntoInnerError},
+};
+
+use fast_image_resize::{ImageBufferError, MulDivImagesError, ResizeError};
+use image::{ImageError, ImageFormat};
+
+/// every failure of this crate
+#[derive(Debug)]
+pub enum Error {
+    /// decoding or encoding failed, the `ImageError` tells whether the input is broken,
+    /// unsupported or over a limit
+    Image(ImageError),
+    /// the resizer rejected the pixels, sizes or crop
+    Resize(Box<dyn std::error::Error + Send + Sync>),
+    /// no encoder for this format, see [`crate::format::ENCODE_FORMATS`]
+    UnsupportedFormat(ImageFormat),
+    /// a filename template that doesn't parse, the message says why
+    Template(String),
+    /// writing the encoded output failed
+    Io(io::Error),
+}
+
+/// `Result` with this crate's [`Error`]
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+impl Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Image(e) => write!(f, "{}", e),
+            Error::Resize(e) => write!(f, "resize failed: {}", e),
+            Error::UnsupportedFormat(format) => {
+                write!(f, "image format {} is not support", format.to_mime_type())
+            }
+            Error::Template(message) => write!(f, "{}", message),
+            Error::Io(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Image(e) => Some(e),
+            Error::Resize(e) => Some(e.as_ref()),
+            Error::Io(e) => Some(e),
+            Error::UnsupportedFormat(_) | Error::Template(_) => None,
+        }
+    }
+}
+
+impl From<ImageError> for Error {
+    fn from(e: ImageError) -> Self {
+        Error::Image(e)
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+impl From<IntoInnerError<BufWriter<Vec<u8>>>> for Error {
+    fn from(e: IntoInnerError<BufWriter<Vec<u8>>>) -> Self {
+        Error::Io(e.into_error())
+    }
+}
+
+impl From<ResizeError> for Error {
+    fn from(e: ResizeError) -> Self {
+        Error::Resize(Box::new(e))
+    }
+}
+
+impl From<fast_image_resize::ImageError> for Error {
+    fn from(e: fast_image_resize::ImageError) -> Self {
+        Error::Resize(Box::new(e))
+    }
+}
+
+impl From<MulDivImagesError> for Error {
+    fn from(e: MulDivImagesError) -> Self {
+        Error::Resize(Box::new(e))
+    }
+}
+
+impl From<ImageBufferError> for Error {
+    fn from(e: ImageBufferError) -> Self {
+        Error::Resize(Box::new(e))
+    }
+}
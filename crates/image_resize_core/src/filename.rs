@@ -0,0 +1,263 @@
+//! output filenames from templates, and keeping archive paths safe and unique
+
+use std::collections::HashSet;
+
+use crate::{Error, Result};
+
+/// keeps the names produced before templates existed
+pub const DEFAULT_TEMPLATE: &str = "@{w}x{h}.{format}";
+
+const PLACEHOLDERS: [&str; 7] = ["name", "w", "h", "scale", "density", "format", "index"];
+
+/// values for one output file
+pub struct FileVars<'a> {
+    /// upload filename without extension
+    pub name: &'a str,
+    /// output width
+    pub width: u32,
+    /// output height
+    pub height: u32,
+    /// output side over source side
+    pub scale: f32,
+    /// extension of the output format
+    pub format: &'a str,
+    /// 1 based position in `sizes`
+    pub index: usize,
+}
+
+/// output filename with `{name}`, `{w}`, `{h}`, `{scale}`, `{density}`, `{format}` and `{index}`
+pub struct FilenameTemplate {
+    template: String,
+}
+
+impl FilenameTemplate {
+    /// fails with [`Error::Template`] on an unknown placeholder or an unclosed brace
+    pub fn parse(template: &str) -> Result<FilenameTemplate> {
+        let mut rest = template;
+        while let Some(start) = rest.find('{') {
+            let end = rest[start..].find('}').ok_or_else(|| {
+                Error::Template("filename template has an unclosed {".to_string())
+            })?;
+            let key = &rest[start + 1..start + end];
+            if !PLACEHOLDERS.contains(&key) {
+                return Err(Error::Template(format!(
+                    "filename template placeholder {{{}}} is unknown",
+                    key
+                )));
+            }
+            rest = &rest[start + end + 1..];
+        }
+
+        if template.trim().is_empty() {
+            return Err(Error::Template("filename template is empty".to_string()));
+        }
+
+        Ok(FilenameTemplate {
+            template: template.to_string(),
+        })
+    }
+
+    /// whether outputs of different sources can share a folder
+    pub fn has_name(&self) -> bool {
+        self.template.contains("{name}")
+    }
+
+    /// the template as parsed
+    pub fn as_str(&self) -> &str {
+        &self.template
+    }
+
+    /// the filename of one output, sanitized like [`sanitize`]
+    pub fn render(&self, vars: &FileVars) -> String {
+        // @2x style suffix, nothing for 1x
+        let density = if vars.scale == 1f32 {
+            String::new()
+        } else {
+            format!("@{}x", vars.scale)
+        };
+
+        // one pass, so a `{w}` inside the upload name stays literal
+        let mut name = String::with_capacity(self.template.len());
+        let mut rest = self.template.as_str();
+        while let Some(start) = rest.find('{') {
+            // parse checked every brace is closed and known
+            let Some(end) = rest[start..].find('}').map(|end| start + end) else {
+                break;
+            };
+            name.push_str(&rest[..start]);
+            match &rest[start + 1..end] {
+                "name" => name.push_str(&vars.name.replace(['/', '\\'], "_")),
+                "w" => name.push_str(&vars.width.to_string()),
+                "h" => name.push_str(&vars.height.to_string()),
+                "scale" => name.push_str(&vars.scale.to_string()),
+                "density" => name.push_str(&density),
+                "format" => name.push_str(vars.format),
+                "index" => name.push_str(&vars.index.to_string()),
+                other => {
+                    name.push('{');
+                    name.push_str(other);
+                    name.push('}');
+                }
+            }
+            rest = &rest[end + 1..];
+        }
+        name.push_str(rest);
+
+        sanitize(&name)
+    }
+}
+
+impl Default for FilenameTemplate {
+    fn default() -> Self {
+        FilenameTemplate {
+            template: DEFAULT_TEMPLATE.to_string(),
+        }
+    }
+}
+
+/// archive paths stay relative and never climb out of the extraction dir, control
+/// characters and quotes are dropped so a name is safe inside a header
+pub fn sanitize(path: &str) -> String {
+    let path: String = path
+        .chars()
+        .filter(|c| !c.is_control() && *c != '"')
+        .collect();
+    let parts: Vec<&str> = path
+        .split(['/', '\\'])
+        .filter(|p| !p.is_empty() && *p != "." && *p != "..")
+        .collect();
+
+    if parts.is_empty() {
+        "image".to_string()
+    } else {
+        parts.join("/")
+    }
+}
+
+/// hands out each name once, repeats get `-1`, `-2`... before the extension
+///
+/// names differing only in case count as repeats, they are one file once extracted on
+/// windows or macos
+#[derive(Default)]
+pub struct UniqueNames {
+    /// lowercased
+    used: HashSet<String>,
+}
+
+impl UniqueNames {
+    /// `name`, or `name` with the first free suffix
+    pub fn claim(&mut self, name: String) -> String {
+        if self.used.insert(name.to_lowercase()) {
+            return name;
+        }
+
+        // only split the extension off the last path segment
+        let file_start = name.rfind('/').map_or(0, |i| i + 1);
+        let (stem, ext) = match name[file_start..].rfind('.') {
+            Some(i) if i > 0 => name.split_at(file_start + i),
+            _ => (name.as_str(), ""),
+        };
+
+        let mut n = 1;
+        loop {
+            let candidate = format!("{}-{}{}", stem, n, ext);
+            if self.used.insert(candidate.to_lowercase()) {
+                return candidate;
+            }
+            n += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use image::ImageFormat;
+
+    use super::*;
+
+    fn vars(format: &str) -> FileVars<'_> {
+        FileVars {
+            name: "cat",
+            width: 200,
+            height: 100,
+            scale: 2f32,
+            format,
+            index: 3,
+        }
+    }
+
+    #[test]
+    fn every_placeholder_is_expanded() {
+        let template =
+            FilenameTemplate::parse("{name}_{w}x{h}_{scale}{density}_{index}.{format}").unwrap();
+
+        assert_eq!(template.render(&vars("png")), "cat_200x100_2@2x_3.png");
+    }
+
+    #[test]
+    fn the_default_template_keeps_the_old_names() {
+        let mut vars = vars("png");
+        vars.scale = 1f32;
+
+        assert_eq!(FilenameTemplate::default().render(&vars), "@200x100.png");
+    }
+
+    #[test]
+    fn format_is_the_extension_of_the_output() {
+        // callers pass the first extension of the output format
+        let template = FilenameTemplate::parse("{name}.{format}").unwrap();
+        let ext = |format: ImageFormat| template.render(&vars(format.extensions_str()[0]));
+
+        assert_eq!(ext(ImageFormat::Jpeg), "cat.jpg");
+        assert_eq!(ext(ImageFormat::Png), "cat.png");
+        assert_eq!(ext(ImageFormat::WebP), "cat.webp");
+        assert_eq!(ext(ImageFormat::Avif), "cat.avif");
+    }
+
+    #[test]
+    fn upload_names_stay_literal_and_flat() {
+        let template = FilenameTemplate::parse("{name}-{w}.{format}").unwrap();
+        let mut vars = vars("png");
+        vars.name = "../{w}/x";
+
+        assert_eq!(template.render(&vars), ".._{w}_x-200.png");
+    }
+
+    #[test]
+    fn unknown_placeholders_and_open_braces_fail() {
+        assert!(FilenameTemplate::parse("{size}.png").is_err());
+        assert!(FilenameTemplate::parse("{name.png").is_err());
+        assert!(FilenameTemplate::parse(" ").is_err());
+    }
+
+    #[test]
+    fn repeats_get_the_next_free_suffix() {
+        let mut names = UniqueNames::default();
+
+        assert_eq!(names.claim("a.png".to_string()), "a.png");
+        assert_eq!(names.claim("a.png".to_string()), "a-1.png");
+        assert_eq!(names.claim("a.png".to_string()), "a-2.png");
+        assert_eq!(names.claim("dir.v2/a".to_string()), "dir.v2/a");
+        assert_eq!(names.claim("dir.v2/a".to_string()), "dir.v2/a-1");
+    }
+
+    #[test]
+    fn names_differing_in_case_are_repeats() {
+        let mut names = UniqueNames::default();
+
+        assert_eq!(names.claim("A.png".to_string()), "A.png");
+        assert_eq!(names.claim("a.PNG".to_string()), "a-1.PNG");
+        assert_eq!(names.claim("A-1.png".to_string()), "A-1-1.png");
+    }
+
+    #[test]
+    fn a_suffix_skips_literal_names() {
+        let mut names = UniqueNames::default();
+
+        assert_eq!(names.claim("a-1.png".to_string()), "a-1.png");
+        assert_eq!(names.claim("a.png".to_string()), "a.png");
+        assert_eq!(names.claim("a.png".to_string()), "a-2.png");
+        // the literal comes after the generated one
+        assert_eq!(names.claim("a-2.png".to_string()), "a-2-1.png");
+    }
+}
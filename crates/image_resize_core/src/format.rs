@@ -0,0 +1,43 @@
+//! which formats can be written and how they are named
+
+use std::io::Cursor;
+
+use image::{DynamicImage, ImageFormat};
+
+use crate::{Error, Result};
+
+/// formats [`Frame::encode_with_quality`](crate::algorithm::Frame::encode_with_quality)
+/// can write
+pub const ENCODE_FORMATS: [ImageFormat; 4] = [
+    ImageFormat::Png,
+    ImageFormat::Jpeg,
+    ImageFormat::WebP,
+    ImageFormat::Avif,
+];
+
+/// formats [`transform`] can write
+pub const TRANSFORM_FORMATS: [ImageFormat; 3] =
+    [ImageFormat::Png, ImageFormat::Jpeg, ImageFormat::WebP];
+
+/// `png`, `jpeg` or `jpg`, `webp` and `avif`, the names clients and the cli use
+pub fn from_name(name: &str) -> Option<ImageFormat> {
+    match name {
+        "png" => Some(ImageFormat::Png),
+        "jpeg" | "jpg" => Some(ImageFormat::Jpeg),
+        "webp" => Some(ImageFormat::WebP),
+        "avif" => Some(ImageFormat::Avif),
+        _ => None,
+    }
+}
+
+/// re-encode a decoded image as is, with the `image` crate's default encoder settings
+pub fn transform(image: &DynamicImage, target_format: ImageFormat) -> Result<Vec<u8>> {
+    if !TRANSFORM_FORMATS.contains(&target_format) {
+        return Err(Error::UnsupportedFormat(target_format));
+    }
+
+    let mut buffer = Cursor::new(Vec::new());
+    image.write_to(&mut buffer, target_format)?;
+
+    Ok(buffer.into_inner())
+}
@@ -0,0 +1,33 @@
+//! Resize, fit and encode images the way the image_resize server does.
+//!
+//! Decode with the `image` crate, turn the image into a [`Frame`] once, then resize it to
+//! as many sizes as needed and encode each one:
+//!
+//! ```no_run
+//! use image::ImageFormat;
+//! use image_resize_core::{algorithm, Frame};
+//!
+//! # fn main() -> image_resize_core::Result<()> {
+//! let image = image::open("photo.png")?;
+//! let frame = Frame::from_image(&image)?;
+//!
+//! let (w, h) = algorithm::target_size(frame.width(), frame.height(), 0.5);
+//! let jpeg = frame.resize(w, h)?.encode_with_quality(ImageFormat::Jpeg, Some(80))?;
+//! std::fs::write("photo@0.5x.jpg", jpeg)?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Resizing and encoding are cpu bound and run on the calling thread,
+//! [`algorithm::resize_all`] spreads one image's sizes over the current rayon pool.
+//! Async callers should move the work off their executor.
+
+#![warn(missing_docs)]
+
+pub mod algorithm;
+mod error;
+pub mod filename;
+pub mod format;
+
+pub use algorithm::{Crop, Fit, Frame, Target};
+pub use error::{Error, Result};
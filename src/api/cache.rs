@@ -0,0 +1,142 @@
+use std::{env, sync::OnceLock};
+
+use poem::{
+    http::{header, Method, StatusCode},
+    Request, Response, ResponseBuilder,
+};
+
+static RESIZE_CACHE_CONTROL: OnceLock<String> = OnceLock::new();
+static IMG_CACHE_CONTROL: OnceLock<String> = OnceLock::new();
+static JOB_RESULT_CACHE_CONTROL: OnceLock<String> = OnceLock::new();
+
+/// endpoints serving derived images, each with its own Cache-Control
+#[derive(Clone, Copy, Debug)]
+pub enum Endpoint {
+    /// /api/resize and /api/resizefree, private and revalidated since ai outputs are paid
+    Resize,
+    /// /img/{key}, public so CDNs can keep it
+    Img,
+    /// /api/jobs/{id}/result, never changes once written
+    JobResult,
+}
+
+impl Endpoint {
+    /// CACHE_CONTROL_RESIZE, CACHE_CONTROL_IMG and CACHE_CONTROL_JOB_RESULT override the defaults
+    pub fn cache_control(&self) -> &'static str {
+        match self {
+            Endpoint::Resize => RESIZE_CACHE_CONTROL
+                .get_or_init(|| from_env("CACHE_CONTROL_RESIZE", "private, no-cache".to_string())),
+            Endpoint::Img => IMG_CACHE_CONTROL.get_or_init(|| {
+                // IMG_CACHE_MAX_AGE seconds, a day by default
+                let max_age = env::var("IMG_CACHE_MAX_AGE")
+                    .ok()
+                    .and_then(|v| v.parse::<u64>().ok())
+                    .unwrap_or(24 * 60 * 60);
+                from_env("CACHE_CONTROL_IMG", format!("public, max-age={}", max_age))
+            }),
+            Endpoint::JobResult => JOB_RESULT_CACHE_CONTROL.get_or_init(|| {
+                from_env(
+                    "CACHE_CONTROL_JOB_RESULT",
+                    "private, max-age=86400, immutable".to_string(),
+                )
+            }),
+        }
+    }
+}
+
+fn from_env(key: &str, default: String) -> String {
+    env::var(key)
+        .ok()
+        .filter(|v| !v.is_empty())
+        .unwrap_or(default)
+}
+
+/// weak, archives and ai outputs are equivalent but not byte for byte the same
+pub fn etag(digest: &str) -> String {
+    format!("W/\"{}\"", digest)
+}
+
+/// If-None-Match is `*` or lists `etag`, compared weakly, only for GET and HEAD since any
+/// other method would owe a 412 rather than a 304
+pub fn is_fresh(req: &Request, etag: &str) -> bool {
+    if req.method() != Method::GET && req.method() != Method::HEAD {
+        return false;
+    }
+
+    let Some(value) = req.header(header::IF_NONE_MATCH) else {
+        return false;
+    };
+
+    let opaque = |tag: &str| tag.trim().trim_start_matches("W/").to_string();
+    let etag = opaque(etag);
+
+    value
+        .split(',')
+        .any(|tag| tag.trim() == "*" || opaque(tag) == etag)
+}
+
+pub fn validators(resp: ResponseBuilder, endpoint: Endpoint, etag: &str) -> ResponseBuilder {
+    resp.header(header::ETAG, etag)
+        .header(header::CACHE_CONTROL, endpoint.cache_control())
+}
+
+pub fn not_modified(endpoint: Endpoint, etag: &str) -> ResponseBuilder {
+    validators(
+        Response::builder().status(StatusCode::NOT_MODIFIED),
+        endpoint,
+        etag,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn req(method: Method, if_none_match: Option<&str>) -> Request {
+        let builder = Request::builder().method(method);
+        match if_none_match {
+            Some(value) => builder.header(header::IF_NONE_MATCH, value).finish(),
+            None => builder.finish(),
+        }
+    }
+
+    #[test]
+    fn only_get_and_head_are_fresh() {
+        let tag = etag("abc");
+
+        assert!(is_fresh(&req(Method::GET, Some(&tag)), &tag));
+        assert!(is_fresh(&req(Method::HEAD, Some(&tag)), &tag));
+        assert!(!is_fresh(&req(Method::POST, Some(&tag)), &tag));
+        assert!(!is_fresh(&req(Method::PUT, Some("*")), &tag));
+    }
+
+    #[test]
+    fn star_matches_any_etag() {
+        assert!(is_fresh(&req(Method::GET, Some("*")), &etag("abc")));
+        assert!(is_fresh(&req(Method::GET, Some("\"x\", *")), &etag("abc")));
+    }
+
+    #[test]
+    fn any_tag_of_a_list_matches_weakly() {
+        let tag = etag("abc");
+
+        assert!(is_fresh(
+            &req(Method::GET, Some("W/\"x\", W/\"abc\",W/\"y\"")),
+            &tag
+        ));
+        // a strong tag with the same opaque value matches a weak one
+        assert!(is_fresh(&req(Method::GET, Some("\"x\", \"abc\"")), &tag));
+        assert!(!is_fresh(
+            &req(Method::GET, Some("W/\"x\", W/\"ab\"")),
+            &tag
+        ));
+    }
+
+    #[test]
+    fn missing_or_empty_header_is_not_fresh() {
+        let tag = etag("abc");
+
+        assert!(!is_fresh(&req(Method::GET, None), &tag));
+        assert!(!is_fresh(&req(Method::GET, Some("")), &tag));
+    }
+}
@@ -0,0 +1,178 @@
+use std::{io::Cursor, time::SystemTime};
+
+use anyhow::{Context, Result};
+use bytes::Bytes;
+use image::{ImageFormat, ImageReader};
+use image_resize_core::{algorithm, format, Fit, Frame};
+use poem::{
+    handler,
+    http::header,
+    web::{Path, Query},
+    Request, Response, ResponseBuilder,
+};
+use poem_openapi::{payload::Json, Object, OpenApi};
+
+use crate::{
+    api::{
+        cache::{self, Endpoint},
+        negotiate,
+        openapi::{ApiTags, TokenAuth},
+        params::img_params::ImgParams,
+    },
+    core::{budget, inspect, pool, signed_url},
+    db::{file, signing_key},
+    status_code::{AppError, AppResult, ErrorCode, ResultExt},
+};
+
+/// longest lifetime of a minted url
+const MAX_EXPIRES_IN: u64 = 365 * 24 * 60 * 60;
+
+const DEFAULT_EXPIRES_IN: u64 = 24 * 60 * 60;
+
+#[derive(Object)]
+struct SignReq {
+    /// `/img/{key}?...` as it will be requested
+    path: String,
+    /// seconds, a day by default
+    expires_in: Option<u64>,
+}
+
+#[derive(Object)]
+struct SignResp {
+    /// `path` with `kid`, `exp` and `sig` appended
+    url: String,
+    kid: String,
+    /// unix seconds
+    exp: u64,
+}
+
+/// transform an original from the object store on the fly, `<img src>` can point here
+#[handler]
+pub async fn img(
+    req: &Request,
+    Path(key): Path<String>,
+    Query(params): Query<ImgParams>,
+) -> AppResult<Response> {
+    file::original_key(&key).await?;
+
+    if !params.validate() {
+        return Err(AppError::invalid_params("params validate fail"));
+    }
+
+    let original = file::get_original(&key)
+        .await
+        .with_context(|| format!("get original {} error", key))
+        .app_err(ErrorCode::Upstream)?
+        .ok_or_else(|| AppError::new(ErrorCode::NotFound, "source key not found"))?;
+
+    // a pinned fmt is the same for every client, otherwise caches must key on Accept
+    let format = params.fmt.as_deref().and_then(format::from_name);
+    let accept = req.header(header::ACCEPT).map(|s| s.to_string());
+
+    let digest = {
+        let (params, original, accept) = (params.clone(), original.clone(), accept.clone());
+        pool::run(move || params.digest(&original, accept.as_deref())).await
+    };
+    let etag = cache::etag(&digest.app_err(ErrorCode::Internal)?);
+
+    let vary = |resp: ResponseBuilder| match format {
+        Some(_) => resp,
+        None => resp.header(header::VARY, "Accept"),
+    };
+
+    if cache::is_fresh(req, &etag) {
+        return Ok(vary(cache::not_modified(Endpoint::Img, &etag)).finish());
+    }
+
+    let fit = params.fit().unwrap_or(Fit::Contain);
+    let (w, h, q) = (params.w, params.h, params.q);
+
+    // a broken header fails in render, only its bytes are budgeted
+    let estimate = match inspect::header(&original) {
+        Ok((size, color)) => {
+            budget::estimate(size, color, [algorithm::fit_size(size, w, h, fit).0])
+        }
+        Err(_) => original.len() as u64,
+    };
+    let _permit = budget::acquire(estimate).await?;
+
+    let (buf, target) = pool::run(move || render(original, w, h, fit, format, accept, q))
+        .await
+        .and_then(|r| r)
+        .with_context(|| format!("render {} error", key))
+        .app_err(ErrorCode::Internal)?;
+
+    Ok(vary(cache::validators(
+        Response::builder().content_type(target.to_mime_type()),
+        Endpoint::Img,
+        &etag,
+    ))
+    .body(buf))
+}
+
+pub struct ImgApi;
+
+#[OpenApi(tag = "ApiTags::Img")]
+impl ImgApi {
+    /// mint a signed /img url with the user's signing key
+    #[oai(path = "/img/sign", method = "post")]
+    async fn sign(&self, req: Json<SignReq>, auth: TokenAuth) -> AppResult<Json<SignResp>> {
+        let Some(key) = req.path.strip_prefix("/img/") else {
+            return Err(AppError::invalid_params("path must start with /img/"));
+        };
+        // a url for a key /img would refuse is no use, and must not be minted
+        file::original_key(key.split('?').next().unwrap_or_default()).await?;
+
+        let expires_in = req.expires_in.unwrap_or(DEFAULT_EXPIRES_IN);
+        if expires_in == 0 || expires_in > MAX_EXPIRES_IN {
+            return Err(AppError::invalid_params("params validate fail"));
+        }
+
+        let key = signing_key::get_or_insert(&auth.0.email)
+            .await
+            .context("get signing key error")
+            .app_err(ErrorCode::Internal)?;
+
+        let exp = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+            + expires_in;
+
+        let url = signed_url::sign_url(&req.path, &key.kid, &key.secret, exp as i64)
+            .context("sign url error")
+            .app_err(ErrorCode::Internal)?;
+
+        Ok(Json(SignResp {
+            url,
+            kid: key.kid,
+            exp,
+        }))
+    }
+}
+
+fn render(
+    original: Bytes,
+    width: Option<u32>,
+    height: Option<u32>,
+    fit: Fit,
+    format: Option<ImageFormat>,
+    accept: Option<String>,
+    quality: Option<u8>,
+) -> Result<(Bytes, ImageFormat)> {
+    let reader = ImageReader::new(Cursor::new(original)).with_guessed_format()?;
+    let source = reader.format().unwrap_or(ImageFormat::Png);
+    let image = reader.decode()?;
+    let format = format.unwrap_or_else(|| {
+        negotiate::image_format(accept.as_deref(), image.color().has_alpha(), source)
+    });
+    let frame = Frame::from_image(&image)?;
+
+    let ((w, h), crop) = algorithm::fit_size((frame.width(), frame.height()), width, height, fit);
+
+    let buf = frame
+        .resize_cropped(w, h, crop)?
+        .encode_with_quality(format, quality)?;
+
+    Ok((buf, format))
+}
@@ -0,0 +1,35 @@
+use poem_openapi::{payload::Json, OpenApi};
+
+use crate::{
+    api::{
+        openapi::ApiTags,
+        params::inspect_params::{ImageInspectParams, InspectForm},
+    },
+    core::{
+        inspect::{inspect as inspect_image, ImageInfo},
+        pool,
+    },
+    status_code::{AppResult, ErrorCode, ResultExt},
+};
+
+pub struct InspectApi;
+
+#[OpenApi(tag = "ApiTags::Inspect")]
+impl InspectApi {
+    /// format, size, color type and exif of an image, pixels are not decoded
+    #[oai(path = "/inspect", method = "post")]
+    async fn inspect(&self, form: InspectForm) -> AppResult<Json<ImageInfo>> {
+        let params = ImageInspectParams::from_form(form)
+            .await
+            .app_err(ErrorCode::InvalidParams)?;
+
+        // header parsing walks every chunk of the file, keep it off the async workers
+        let blob = params.blob;
+        let info = pool::run(move || inspect_image(&blob))
+            .await
+            .app_err(ErrorCode::Internal)?
+            .app_err(ErrorCode::InvalidImage)?;
+
+        Ok(Json(info))
+    }
+}
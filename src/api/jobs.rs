@@ -0,0 +1,573 @@
+use std::{
+    collections::HashMap,
+    env,
+    fs::File,
+    io::BufWriter,
+    iter,
+    sync::{Arc, Mutex, OnceLock},
+    time::Duration,
+};
+
+use anyhow::{Context, Error, Result};
+use bytes::Bytes;
+use chrono::{DateTime, Local};
+use poem::{http::header, Body, Request};
+use poem_openapi::{param::Path, payload::Json, ApiResponse, Object, OpenApi};
+use serde::Serialize;
+use tokio::sync::{
+    mpsc::{self, UnboundedSender},
+    Semaphore,
+};
+use tracing::{error, info};
+
+use crate::{
+    api::{
+        cache::{self, Endpoint},
+        openapi::ApiTags,
+        params::resize_params::{read_body, ImageResizeParams, ResizeBody, Tier},
+        resize::{self, check_credits, negotiate_format},
+        stream::{self, Download, Entry, Output},
+    },
+    core::{budget, webhook},
+    db::{
+        job::{self, Callback, Delivery, Job, JobStatus, SizeProgress},
+        user,
+    },
+    middleware::auth::get_auth_claims,
+    status_code::{AppError, AppResult, ErrorCode, ResultExt},
+};
+
+/// how often finished jobs past retention are deleted
+const SWEEP_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+/// longest wait between two callback attempts
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(60 * 60);
+
+static WEBHOOK_CONFIG: OnceLock<WebhookConfig> = OnceLock::new();
+
+struct WebhookConfig {
+    max_attempts: u32,
+    retry_delay: Duration,
+}
+
+/// WEBHOOK_MAX_ATTEMPTS tries, the first retry after WEBHOOK_RETRY_SECS, doubling after that
+fn get_webhook_config() -> &'static WebhookConfig {
+    WEBHOOK_CONFIG.get_or_init(|| WebhookConfig {
+        max_attempts: env::var("WEBHOOK_MAX_ATTEMPTS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(6),
+        retry_delay: env::var("WEBHOOK_RETRY_SECS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .map(Duration::from_secs)
+            .unwrap_or(Duration::from_secs(10)),
+    })
+}
+
+static QUEUE: OnceLock<UnboundedSender<String>> = OnceLock::new();
+
+/// progress of running jobs, written to the job file once they finish
+static PROGRESS: OnceLock<Mutex<HashMap<String, Vec<SizeProgress>>>> = OnceLock::new();
+
+fn get_progress() -> &'static Mutex<HashMap<String, Vec<SizeProgress>>> {
+    PROGRESS.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+#[derive(Object)]
+struct JobView {
+    id: String,
+    status: JobStatus,
+    /// one entry per size, batches count every image
+    progress: Vec<SizeProgress>,
+    error: Option<String>,
+    /// ai credits charged
+    credits: i64,
+    /// download url once the job is done
+    result_url: Option<String>,
+    callback: Option<Callback>,
+    ctime: DateTime<Local>,
+    mtime: DateTime<Local>,
+}
+
+/// body of the completion callback
+#[derive(Serialize)]
+struct CallbackPayload<'a> {
+    job_id: &'a str,
+    status: JobStatus,
+    result_url: Option<String>,
+    error: Option<&'a str>,
+    credits: i64,
+}
+
+impl JobView {
+    fn from(job: &Job) -> Self {
+        JobView {
+            id: job.id.clone(),
+            status: job.status,
+            progress: job.progress.clone(),
+            error: job.error.clone(),
+            credits: job.credits,
+            result_url: result_url(job),
+            callback: job.callback.clone(),
+            ctime: job.ctime,
+            mtime: job.mtime,
+        }
+    }
+}
+
+/// PUBLIC_BASE_URL makes it absolute for webhook receivers
+fn result_url(job: &Job) -> Option<String> {
+    if job.status != JobStatus::Done {
+        return None;
+    }
+
+    let base = env::var("PUBLIC_BASE_URL").unwrap_or_default();
+    Some(format!(
+        "{}/api/jobs/{}/result",
+        base.trim_end_matches('/'),
+        job.id
+    ))
+}
+
+#[derive(ApiResponse)]
+enum SubmitResponse {
+    /// queued, poll the job or wait for the callback
+    #[oai(status = 202)]
+    Accepted(Json<JobView>),
+}
+
+#[derive(ApiResponse)]
+enum ResultResponse {
+    /// the response body the resize would have streamed
+    #[oai(status = 200)]
+    Ok(
+        Download,
+        #[oai(header = "ETag")] String,
+        #[oai(header = "Cache-Control")] String,
+    ),
+    #[oai(status = 304)]
+    NotModified(
+        #[oai(header = "ETag")] String,
+        #[oai(header = "Cache-Control")] String,
+    ),
+}
+
+pub struct JobsApi;
+
+/// a token is optional, jobs with one are only visible with the same token
+#[OpenApi(tag = "ApiTags::Jobs")]
+impl JobsApi {
+    /// same body as /api/resize, the job runs in the background and the id comes back at once
+    ///
+    /// without a token only algorithm sizes are allowed, like /api/resizefree
+    #[oai(path = "/jobs", method = "post")]
+    async fn submit(&self, req: &Request, body: ResizeBody) -> AppResult<SubmitResponse> {
+        let owner = match req.header(header::AUTHORIZATION) {
+            Some(_) => match get_auth_claims(req) {
+                Some(claims) => Some(claims.email.clone()),
+                None => return Err(AppError::new(ErrorCode::Unauthorized, "token is invalid")),
+            },
+            None => None,
+        };
+
+        // the same limits as the sync endpoints, checked before anything is stored
+        let request = read_body(req, body.0)
+            .await
+            .app_err(ErrorCode::InvalidParams)?;
+
+        // parse once here so a bad request fails now instead of in the worker
+        let params =
+            ImageResizeParams::from_request(req, Body::from(request.clone()), owner.as_deref())
+                .await
+                .app_err(ErrorCode::InvalidParams)?;
+
+        params.validate(if owner.is_some() {
+            Tier::User
+        } else {
+            Tier::Free
+        })?;
+
+        if owner.is_none() && params.sizes.iter().any(|ele| ele.use_ai) {
+            return Err(AppError::new(
+                ErrorCode::Unauthorized,
+                "use_ai needs a login",
+            ));
+        }
+
+        if negotiate_format(req, &params).is_none() {
+            return Err(AppError::invalid_params("response format not support"));
+        }
+
+        if let Some(url) = &params.callback_url {
+            if !webhook::is_configured() {
+                return Err(AppError::invalid_params("callback_url not support"));
+            }
+
+            webhook::check(url)
+                .await
+                .context("callback_url is not allowed")
+                .app_err(ErrorCode::InvalidParams)?;
+        }
+
+        let job = Job::new(
+            owner,
+            req.content_type().unwrap_or_default().to_string(),
+            req.header(header::ACCEPT).map(|a| a.to_string()),
+            params.callback_url.clone(),
+        );
+
+        job::insert(&job, &request)
+            .await
+            .context("insert job error")
+            .app_err(ErrorCode::Internal)?;
+
+        enqueue(job.id.clone());
+
+        Ok(SubmitResponse::Accepted(Json(JobView::from(&job))))
+    }
+
+    /// state and per-size progress of a job
+    #[oai(path = "/jobs/:id", method = "get")]
+    async fn status(&self, req: &Request, id: Path<String>) -> AppResult<Json<JobView>> {
+        let mut job = find(req, &id).await?;
+
+        if let Some(progress) = get_progress().lock().unwrap().get(&job.id) {
+            job.progress = progress.clone();
+        }
+
+        Ok(Json(JobView::from(&job)))
+    }
+
+    /// download the output of a finished job
+    #[oai(path = "/jobs/:id/result", method = "get")]
+    async fn result(&self, req: &Request, id: Path<String>) -> AppResult<ResultResponse> {
+        let job = find(req, &id).await?;
+
+        if job.status != JobStatus::Done {
+            return Err(AppError::new(
+                ErrorCode::Conflict,
+                format!("job is {:?}", job.status).to_lowercase(),
+            ));
+        }
+
+        // a job's result never changes
+        let etag = cache::etag(&job.id);
+        let cache_control = Endpoint::JobResult.cache_control().to_string();
+        if cache::is_fresh(req, &etag) {
+            return Ok(ResultResponse::NotModified(etag, cache_control));
+        }
+
+        let path = job::result_path(&job.id).app_err(ErrorCode::Internal)?;
+        let file = tokio::fs::File::open(path)
+            .await
+            .context("open job result error")
+            .app_err(ErrorCode::Internal)?;
+
+        Ok(ResultResponse::Ok(
+            Download {
+                content_type: job
+                    .result_type
+                    .unwrap_or_else(|| "application/octet-stream".to_string()),
+                body: Body::from_async_read(file),
+            },
+            etag,
+            cache_control,
+        ))
+    }
+}
+
+/// jobs with an owner are only visible to them, to anyone else they don't exist
+async fn find(req: &Request, id: &str) -> AppResult<Job> {
+    let not_found = || AppError::new(ErrorCode::NotFound, "job not found");
+
+    let job = job::get(id).await.ok().flatten().ok_or_else(not_found)?;
+
+    match &job.email {
+        Some(email) => match get_auth_claims(req) {
+            Some(claims) if &claims.email == email => Ok(job),
+            _ => Err(not_found()),
+        },
+        None => Ok(job),
+    }
+}
+
+fn enqueue(id: String) {
+    match QUEUE.get() {
+        Some(queue) => {
+            let _ = queue.send(id);
+        }
+        None => error!("job worker is not started, job {} stays queued", id),
+    }
+}
+
+/// JOB_WORKERS jobs run at once, finished jobs are kept JOB_RETENTION_SECS
+pub async fn start_worker() {
+    let workers = env::var("JOB_WORKERS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(2);
+    let retention = env::var("JOB_RETENTION_SECS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .map(Duration::from_secs)
+        .unwrap_or(Duration::from_secs(24 * 60 * 60));
+
+    let (tx, mut rx) = mpsc::unbounded_channel::<String>();
+    if QUEUE.set(tx).is_err() {
+        return;
+    }
+
+    let semaphore = Arc::new(Semaphore::new(workers));
+    tokio::spawn(async move {
+        while let Some(id) = rx.recv().await {
+            let Ok(permit) = semaphore.clone().acquire_owned().await else {
+                break;
+            };
+            tokio::spawn(async move {
+                run(&id).await;
+                drop(permit);
+            });
+        }
+    });
+
+    // jobs and callbacks a restart interrupted start over
+    match job::list().await {
+        Ok(mut jobs) => {
+            jobs.sort_by_key(|job| job.ctime);
+            for job in jobs {
+                if !job.status.is_finished() {
+                    info!("resume job {}", job.id);
+                    enqueue(job.id);
+                } else if job.callback.as_ref().is_some_and(|c| !c.delivered) {
+                    tokio::spawn(notify(job));
+                }
+            }
+        }
+        Err(e) => error!("list jobs error: {:?}", e),
+    }
+
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(SWEEP_INTERVAL);
+        loop {
+            interval.tick().await;
+            if let Err(e) = sweep(retention).await {
+                error!("sweep jobs error: {:?}", e);
+            }
+        }
+    });
+}
+
+async fn run(id: &str) {
+    let mut job = match job::get(id).await {
+        Ok(Some(job)) if !job.status.is_finished() => job,
+        Ok(_) => return,
+        Err(e) => {
+            error!("get job {} error: {:?}", id, e);
+            return;
+        }
+    };
+
+    job.status = JobStatus::Running;
+    job.mtime = Local::now();
+    if let Err(e) = job::update(&job).await {
+        error!("update job {} error: {:?}", id, e);
+        return;
+    }
+
+    let r = process(&mut job).await;
+
+    if let Some(progress) = get_progress().lock().unwrap().remove(id) {
+        job.progress = progress;
+    }
+
+    match r {
+        Ok(credits) => {
+            job.status = JobStatus::Done;
+            job.credits = credits;
+        }
+        Err(e) => {
+            error!("job {} error: {:?}", id, e);
+            job.status = JobStatus::Failed;
+            job.error = Some(e.to_string());
+        }
+    }
+
+    job.mtime = Local::now();
+    if let Err(e) = job::update(&job).await {
+        error!("update job {} error: {:?}", id, e);
+    }
+
+    let _ = job::remove_request(id).await;
+
+    if job.callback.is_some() {
+        tokio::spawn(notify(job));
+    }
+}
+
+/// post the completion callback until the receiver answers 2xx, every attempt is
+/// logged on the job
+async fn notify(mut job: Job) {
+    let config = get_webhook_config();
+
+    let body = serde_json::to_vec(&CallbackPayload {
+        job_id: &job.id,
+        status: job.status,
+        result_url: result_url(&job),
+        error: job.error.as_deref(),
+        credits: job.credits,
+    });
+    let body = match body {
+        Ok(body) => Bytes::from(body),
+        Err(e) => {
+            error!("callback payload error: {:?}", e);
+            return;
+        }
+    };
+
+    loop {
+        let Some(callback) = job.callback.as_mut() else {
+            return;
+        };
+
+        let attempt = callback.deliveries.len() as u32 + 1;
+        if callback.delivered || attempt > config.max_attempts {
+            return;
+        }
+
+        if attempt > 1 {
+            let delay = config
+                .retry_delay
+                .saturating_mul(2u32.saturating_pow(attempt - 2))
+                .min(MAX_RETRY_DELAY);
+            tokio::time::sleep(delay).await;
+        }
+
+        let r = webhook::post(&callback.url, body.clone()).await;
+
+        let delivery = match r {
+            Ok(code) => {
+                callback.delivered = code.is_success();
+                Delivery {
+                    attempt,
+                    time: Local::now(),
+                    status: Some(code.as_u16()),
+                    error: None,
+                }
+            }
+            Err(e) => Delivery {
+                attempt,
+                time: Local::now(),
+                status: None,
+                error: Some(e.to_string()),
+            },
+        };
+        info!(
+            "job {} callback attempt {}: {:?}",
+            job.id, attempt, delivery.status
+        );
+        callback.deliveries.push(delivery);
+
+        // the job was swept meanwhile
+        if let Err(e) = job::update(&job).await {
+            error!("update job {} error: {:?}", job.id, e);
+            return;
+        }
+    }
+}
+
+/// replay the stored request into the result file, returns the credits charged
+async fn process(job: &mut Job) -> Result<i64> {
+    let request = job::get_request(&job.id).await?;
+
+    let mut builder = Request::builder().content_type(&job.content_type);
+    if let Some(accept) = &job.accept {
+        builder = builder.header(header::ACCEPT, accept);
+    }
+    let req = builder.finish();
+
+    let params =
+        ImageResizeParams::from_request(&req, Body::from(request), job.email.as_deref()).await?;
+    params.validate(if job.email.is_some() {
+        Tier::User
+    } else {
+        Tier::Free
+    })?;
+
+    let format =
+        negotiate_format(&req, &params).ok_or_else(|| Error::msg("response format not support"))?;
+
+    let user = match &job.email {
+        Some(email) => Some(
+            user::get_by_email(email)
+                .await?
+                .ok_or_else(|| Error::msg("user not found"))?,
+        ),
+        None => None,
+    };
+
+    check_credits(&params, user.as_ref())?;
+
+    job.result_type = Some(format.content_type(params.target_img_type()));
+    job.progress = params
+        .sizes
+        .iter()
+        .map(|ele| SizeProgress {
+            scale: ele.scale,
+            use_ai: ele.use_ai,
+            width: ele.width,
+            height: ele.height,
+            done: 0,
+            total: params.images.len(),
+        })
+        .collect();
+    job::update(job).await?;
+
+    get_progress()
+        .lock()
+        .unwrap()
+        .insert(job.id.clone(), job.progress.clone());
+
+    let path = job::result_path(&job.id)?;
+    let id = job.id.clone();
+    let compression = params.compression;
+
+    // the job is already queued, it waits for the budget instead of failing
+    let permit = budget::wait(params.memory_estimate()).await;
+
+    let task = resize::start(params, user, permit, move |mut entries| {
+        let out = BufWriter::new(File::create(path)?);
+
+        let outputs = iter::from_fn(move || entries.blocking_recv()).inspect(move |output| {
+            if let Ok(Output::Entry(Entry { size: Some(i), .. })) = output {
+                if let Some(progress) = get_progress().lock().unwrap().get_mut(&id) {
+                    progress[*i].done += 1;
+                }
+            }
+        });
+
+        stream::write_outputs(&format, compression, outputs, out)
+    })
+    .await?;
+
+    task.await?
+}
+
+/// delete finished jobs older than `retention`
+async fn sweep(retention: Duration) -> Result<()> {
+    let now = Local::now();
+
+    for job in job::list().await? {
+        let expired = now
+            .signed_duration_since(job.mtime)
+            .to_std()
+            .is_ok_and(|age| age > retention);
+
+        if job.status.is_finished() && expired {
+            info!("remove expired job {}", job.id);
+            job::remove(&job.id).await?;
+        }
+    }
+
+    Ok(())
+}
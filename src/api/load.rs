@@ -0,0 +1,20 @@
+use poem_openapi::{payload::Json, OpenApi};
+
+use crate::{
+    api::openapi::{ApiTags, TokenAuth},
+    core::budget::{self, BudgetStats},
+    status_code::AppResult,
+};
+
+pub struct LoadApi;
+
+#[OpenApi(tag = "ApiTags::Load")]
+impl LoadApi {
+    /// memory budget of image work, its queue and how long work waited, admins only
+    #[oai(path = "/load/stats", method = "get")]
+    async fn stats(&self, auth: TokenAuth) -> AppResult<Json<BudgetStats>> {
+        auth.require_admin()?;
+
+        Ok(Json(budget::stats()))
+    }
+}
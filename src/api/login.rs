@@ -4,15 +4,13 @@ use crate::db::user::{self, User};
 use anyhow::Result;
 use google_oauth::{AsyncClient, GooglePayload};
 use jsonwebtoken::{decode, encode, DecodingKey, EncodingKey, Header, Validation};
-use poem::{
-    handler,
-    http::StatusCode,
-    web::{Json, Query},
-    IntoResponse, Response,
-};
+use poem_openapi::{param::Query, payload::Json, Object, OpenApi};
 use serde::{Deserialize, Serialize};
 use tracing::error;
 
+use super::openapi::ApiTags;
+use crate::status_code::{AppError, AppResult, ErrorCode};
+
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct Claims {
     pub username: String,
@@ -45,13 +43,13 @@ fn get_token_secret() -> &'static String {
     TOKEN_SECRET.get_or_init(|| env::var("TOKEN_SECRET").expect("TOKEN_SECRET is not set"))
 }
 
-#[derive(Serialize)]
+#[derive(Object)]
 struct LoginResp {
     token: String,
     meta: UserMeta,
 }
 
-#[derive(Serialize)]
+#[derive(Object)]
 struct UserMeta {
     pub username: String,
     pub email: String,
@@ -108,29 +106,30 @@ fn get_client() -> &'static AsyncClient {
     CLIENT.get_or_init(|| AsyncClient::new(client_id))
 }
 
-#[derive(Deserialize)]
-struct LoginParams {
-    token: String,
+pub struct LoginApi;
+
+#[OpenApi(tag = "ApiTags::Account")]
+impl LoginApi {
+    /// exchange a google id token for an api token valid for a day
+    #[oai(path = "/login", method = "get")]
+    async fn login(&self, token: Query<String>) -> AppResult<Json<LoginResp>> {
+        login(&token).await
+    }
 }
 
-#[handler]
-pub async fn login(Query(LoginParams { token }): Query<LoginParams>) -> Response {
-    let r = get_client().validate_id_token(&token).await;
+async fn login(token: &str) -> AppResult<Json<LoginResp>> {
+    let r = get_client().validate_id_token(token).await;
     if r.is_err() {
         let body = format!("google validate_id_token error: {}", r.err().unwrap());
         error!(body);
-        return Response::builder()
-            .status(StatusCode::BAD_REQUEST)
-            .body(body);
+        return Err(AppError::new(ErrorCode::Unauthorized, body));
     }
 
     let r = r.unwrap();
 
     if let Err(e) = validate_google_payload(&r) {
         error!("google account validate error: {:?}", r);
-        return Response::builder()
-            .status(StatusCode::BAD_REQUEST)
-            .body(e.to_string());
+        return Err(AppError::new(ErrorCode::Unauthorized, e.to_string()));
     }
 
     let email = r.email.unwrap();
@@ -141,9 +140,7 @@ pub async fn login(Query(LoginParams { token }): Query<LoginParams>) -> Response
 
     if u.is_err() {
         error!("get user by email error: {}", u.err().unwrap());
-        return Response::builder()
-            .status(StatusCode::INTERNAL_SERVER_ERROR)
-            .body("db error");
+        return Err(AppError::internal("db error"));
     }
 
     let u = u.unwrap();
@@ -154,9 +151,7 @@ pub async fn login(Query(LoginParams { token }): Query<LoginParams>) -> Response
 
         if insert_r.is_err() {
             error!("insert user error: {}", insert_r.err().unwrap());
-            return Response::builder()
-                .status(StatusCode::INTERNAL_SERVER_ERROR)
-                .body("db error");
+            return Err(AppError::internal("db error"));
         } else {
             user = insert_r.unwrap();
         }
@@ -166,7 +161,7 @@ pub async fn login(Query(LoginParams { token }): Query<LoginParams>) -> Response
 
     let meta = Claims::new(name, email, picture);
 
-    Json(LoginResp::from(&meta, &user)).into_response()
+    Ok(Json(LoginResp::from(&meta, &user)))
 }
 
 fn validate_google_payload(payload: &GooglePayload) -> Result<()> {
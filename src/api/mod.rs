@@ -1,11 +1,15 @@
+mod cache;
+pub mod img;
+pub mod inspect;
+pub mod jobs;
+pub mod load;
 pub mod login;
+pub mod openapi;
+pub mod palette;
+pub mod presets;
 pub mod resize;
+pub mod result_cache;
 
+mod negotiate;
 mod params;
-
-use poem::{http::StatusCode, Response};
-fn gen_known_err_response(msg: &str) -> Response {
-    Response::builder()
-        .status(StatusCode::UNAVAILABLE_FOR_LEGAL_REASONS)
-        .body(msg.to_string())
-}
+mod stream;
@@ -0,0 +1,49 @@
+use image::ImageFormat;
+use image_resize_core::format::ENCODE_FORMATS;
+
+/// avif and webp when listed in Accept with q > 0, wildcards don't count since browsers
+/// send them for everything
+pub fn accepted_formats(accept: Option<&str>) -> Vec<ImageFormat> {
+    let media: Vec<&str> = accept
+        .unwrap_or("")
+        .split(',')
+        .filter_map(|range| {
+            let mut parts = range.split(';').map(str::trim);
+            let media = parts.next().unwrap_or("");
+            let refused = parts
+                .filter_map(|p| p.strip_prefix("q="))
+                .any(|q| q.parse::<f32>().is_ok_and(|q| q == 0.0));
+            (!refused).then_some(media)
+        })
+        .collect();
+
+    [ImageFormat::Avif, ImageFormat::WebP]
+        .into_iter()
+        .filter(|f| media.contains(&f.to_mime_type()))
+        .collect()
+}
+
+/// output format for a client that didn't pin one
+///
+/// avif when accepted, lossless webp when accepted and the image has alpha or the source
+/// is not a jpeg (a photo is smaller as jpeg), otherwise the source format, png or jpeg
+/// when the source can't be encoded
+pub fn image_format(accept: Option<&str>, has_alpha: bool, source: ImageFormat) -> ImageFormat {
+    let accepted = accepted_formats(accept);
+
+    if accepted.contains(&ImageFormat::Avif) {
+        return ImageFormat::Avif;
+    }
+
+    if accepted.contains(&ImageFormat::WebP) && (has_alpha || source != ImageFormat::Jpeg) {
+        return ImageFormat::WebP;
+    }
+
+    if ENCODE_FORMATS.contains(&source) {
+        source
+    } else if has_alpha {
+        ImageFormat::Png
+    } else {
+        ImageFormat::Jpeg
+    }
+}
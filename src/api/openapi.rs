@@ -0,0 +1,102 @@
+use std::{env, sync::OnceLock};
+
+use poem::Request;
+use poem_openapi::{auth::Bearer, OpenApiService, SecurityScheme, Tags};
+
+use crate::{
+    api::{
+        img::ImgApi,
+        inspect::InspectApi,
+        jobs::JobsApi,
+        load::LoadApi,
+        login::{decode_from_token, Claims, LoginApi},
+        palette::PaletteApi,
+        presets::PresetsApi,
+        resize::ResizeApi,
+        result_cache::CacheApi,
+    },
+    status_code::{AppError, AppResult, ErrorCode},
+};
+
+/// path the api is nested under
+pub const API_PREFIX: &str = "/api";
+
+static ADMIN_EMAILS: OnceLock<Vec<String>> = OnceLock::new();
+
+/// ADMIN_EMAILS, separated by commas, nobody when unset
+fn get_admin_emails() -> &'static [String] {
+    ADMIN_EMAILS.get_or_init(|| {
+        env::var("ADMIN_EMAILS")
+            .unwrap_or_default()
+            .split(',')
+            .map(|email| email.trim().to_lowercase())
+            .filter(|email| !email.is_empty())
+            .collect()
+    })
+}
+
+#[derive(Tags)]
+pub enum ApiTags {
+    /// google sign in and api tokens
+    Account,
+    /// resize one image, several or a zip
+    Resize,
+    /// named resize options saved per user
+    Presets,
+    /// palette and metadata of an image
+    Inspect,
+    /// resize in the background and fetch the result later
+    Jobs,
+    /// signed /img urls
+    Img,
+    /// result cache
+    Cache,
+    /// memory budget and queue of image work
+    Load,
+}
+
+/// `Authorization: Bearer <token>` with a token from /api/login
+#[derive(SecurityScheme)]
+#[oai(ty = "bearer", checker = "check_token")]
+pub struct TokenAuth(pub Claims);
+
+async fn check_token(_req: &Request, bearer: Bearer) -> Option<Claims> {
+    decode_from_token(&bearer.token).ok()
+}
+
+impl TokenAuth {
+    /// 403 unless the token belongs to one of ADMIN_EMAILS
+    pub fn require_admin(&self) -> AppResult<()> {
+        let email = self.0.email.to_lowercase();
+        if get_admin_emails().contains(&email) {
+            Ok(())
+        } else {
+            Err(AppError::new(ErrorCode::Forbidden, "admins only"))
+        }
+    }
+}
+
+pub type Api = (
+    LoginApi,
+    ResizeApi,
+    PresetsApi,
+    PaletteApi,
+    InspectApi,
+    JobsApi,
+    ImgApi,
+    CacheApi,
+    LoadApi,
+);
+
+/// every typed endpoint, the spec and swagger ui come from here
+pub fn service() -> OpenApiService<Api, ()> {
+    OpenApiService::new(
+        (
+            LoginApi, ResizeApi, PresetsApi, PaletteApi, InspectApi, JobsApi, ImgApi, CacheApi,
+            LoadApi,
+        ),
+        "image_resize",
+        env!("CARGO_PKG_VERSION"),
+    )
+    .server(API_PREFIX)
+}
@@ -0,0 +1,50 @@
+use poem::Request;
+use poem_openapi::{payload::Json, OpenApi};
+
+use crate::{
+    api::{
+        openapi::ApiTags,
+        params::resize_params::{ImageResizeParams, ResizeBody},
+    },
+    core::{
+        budget,
+        palette::{extract, Palette, DEFAULT_PALETTE_SIZE},
+        pool,
+    },
+    middleware::auth::get_auth_claims,
+    status_code::{AppError, AppResult, ErrorCode, ResultExt},
+};
+
+pub struct PaletteApi;
+
+#[OpenApi(tag = "ApiTags::Inspect")]
+impl PaletteApi {
+    /// dominant colors of one image, `palette` sets how many
+    #[oai(path = "/palette", method = "post")]
+    async fn palette(&self, req: &Request, body: ResizeBody) -> AppResult<Json<Palette>> {
+        // presets work with a token, palette itself needs none
+        let owner = get_auth_claims(req).map(|claims| claims.email);
+        let params = ImageResizeParams::from_request(req, body.0, owner.as_deref())
+            .await
+            .app_err(ErrorCode::InvalidParams)?;
+
+        params.validate_palette()?;
+
+        if params.batch {
+            return Err(AppError::invalid_params("palette takes one image"));
+        }
+
+        let count = params.palette.unwrap_or(DEFAULT_PALETTE_SIZE);
+
+        let _permit = budget::acquire(params.images[0].memory_estimate(&[])).await?;
+        let image = params.images[0]
+            .image()
+            .await
+            .app_err(ErrorCode::InvalidImage)?;
+        let p = pool::run(move || extract(&image, count))
+            .await
+            .app_err(ErrorCode::Internal)?;
+
+        Ok(Json(p))
+    }
+}
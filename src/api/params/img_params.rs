@@ -0,0 +1,77 @@
+use image_resize_core::{format, Fit};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+
+use crate::api::negotiate;
+
+/// largest side /img will produce
+pub const MAX_DIMENSION: u32 = 8192;
+
+/// query of /img/{key}, every field is optional
+#[derive(Deserialize, Debug, Clone)]
+pub struct ImgParams {
+    pub w: Option<u32>,
+    pub h: Option<u32>,
+    /// contain (default), cover or fill, only used when both sides are given
+    pub fit: Option<String>,
+    /// png, jpeg, jpg, webp or avif, negotiated from Accept when missing
+    pub fmt: Option<String>,
+    /// jpeg and avif quality 1-100
+    pub q: Option<u8>,
+}
+
+impl ImgParams {
+    pub fn validate(&self) -> bool {
+        for side in [self.w, self.h].into_iter().flatten() {
+            if side == 0 || side > MAX_DIMENSION {
+                return false;
+            }
+        }
+
+        if let Some(q) = self.q {
+            if q == 0 || q > 100 {
+                return false;
+            }
+        }
+
+        if let Some(fmt) = &self.fmt {
+            if format::from_name(fmt).is_none() {
+                return false;
+            }
+        }
+
+        self.fit().is_some()
+    }
+
+    pub fn fit(&self) -> Option<Fit> {
+        match &self.fit {
+            Some(name) => Fit::from_name(name),
+            None => Some(Fit::Contain),
+        }
+    }
+
+    /// sha256 of the original and the normalised query, `fmt` falls back to what
+    /// Accept allows since that decides the negotiated format
+    pub fn digest(&self, original: &[u8], accept: Option<&str>) -> String {
+        let formats = match self.fmt.as_deref().and_then(format::from_name) {
+            Some(format) => vec![format],
+            None => negotiate::accepted_formats(accept),
+        };
+        let formats: Vec<&str> = formats.iter().map(|f| f.extensions_str()[0]).collect();
+
+        let query = format!(
+            "w={:?}&h={:?}&fit={:?}&fmt={}&q={:?}",
+            self.w,
+            self.h,
+            self.fit(),
+            formats.join(","),
+            self.q
+        );
+
+        let mut hasher = Sha256::new();
+        hasher.update(Sha256::digest(original));
+        hasher.update(query.as_bytes());
+
+        format!("{:x}", hasher.finalize())
+    }
+}
@@ -0,0 +1,28 @@
+use anyhow::{Error, Result};
+use bytes::Bytes;
+use poem_openapi::{types::multipart::Upload, Multipart};
+
+/// multipart body of /api/inspect
+#[derive(Multipart)]
+pub struct InspectForm {
+    blob: Option<Upload>,
+}
+
+pub struct ImageInspectParams {
+    pub blob: Bytes,
+}
+
+impl ImageInspectParams {
+    pub async fn from_form(form: InspectForm) -> Result<ImageInspectParams> {
+        let blob = match form.blob {
+            Some(upload) => Bytes::from(upload.into_vec().await?),
+            None => Bytes::new(),
+        };
+
+        if blob.is_empty() {
+            return Err(Error::msg("upload image is empty"));
+        }
+
+        Ok(ImageInspectParams { blob })
+    }
+}
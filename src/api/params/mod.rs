@@ -1 +1,3 @@
+pub mod img_params;
+pub mod inspect_params;
 pub mod resize_params;
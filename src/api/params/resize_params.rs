@@ -1,136 +1,1173 @@
-use std::io::Cursor;
+use std::{
+    env,
+    io::{Cursor, Read},
+    path::Path,
+    sync::{Arc, OnceLock},
+};
 
 use anyhow::{Error, Result};
-use image::{DynamicImage, ImageReader};
-use poem::web::Multipart;
-use serde::Deserialize;
+use base64::{
+    prelude::{BASE64_STANDARD, BASE64_URL_SAFE},
+    Engine,
+};
+use bytes::Bytes;
+use image::{ColorType, DynamicImage, ImageFormat, ImageReader};
+use image_resize_core::{
+    filename::{self, FilenameTemplate},
+    format, Fit, Target,
+};
+use poem::{error::ReadBodyError, http::header, Body, Request, RequestBody};
+use poem_openapi::{
+    payload::{Json, ParsePayload, Payload},
+    registry::{MetaMediaType, MetaRequest, Registry},
+    types::multipart::{JsonField, Upload},
+    ApiExtractor, ApiExtractorType, ExtractParamOptions, Multipart, Object,
+};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use url::Url;
+use zip::ZipArchive;
+
+use crate::{
+    api::{negotiate, resize::WRITER_BACKLOG, stream::ZipCompression},
+    core::{budget, fetch, inspect, palette, pool},
+    db::preset::{self, PresetOptions},
+    status_code::{AppError, AppResult, ErrorCode, FieldError},
+};
+
+/// images in one batch request, zip entries included
+pub const MAX_BATCH_IMAGES: usize = 200;
+
+/// uncompressed bytes accepted from one zip upload
+const MAX_ZIP_BYTES: u64 = 512 * 1024 * 1024;
+
+const ZIP_MAGIC: &[u8] = b"PK\x03\x04";
+
+/// json body on top of the base64 image, sizes, template and the like
+const JSON_FIELDS_BYTES: usize = 64 * 1024;
+
+/// largest scale of a size, algorithm or ai
+pub const MAX_SCALE: f32 = 8.0;
+
+/// largest side of an output
+pub const MAX_OUTPUT_SIDE: u32 = 16384;
+
+static SIZE_LIMITS: OnceLock<SizeLimits> = OnceLock::new();
+
+struct SizeLimits {
+    free: usize,
+    user: usize,
+}
+
+/// MAX_SIZES_FREE sizes per request without a login, MAX_SIZES_USER with one
+fn get_size_limits() -> &'static SizeLimits {
+    SIZE_LIMITS.get_or_init(|| SizeLimits {
+        free: env::var("MAX_SIZES_FREE")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(8),
+        user: env::var("MAX_SIZES_USER")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(32),
+    })
+}
+
+/// who is asking, limits depend on it
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Tier {
+    /// no login, /api/resizefree and anonymous jobs
+    Free,
+    User,
+}
+
+impl Tier {
+    pub fn max_sizes(self) -> usize {
+        match self {
+            Tier::Free => get_size_limits().free,
+            Tier::User => get_size_limits().user,
+        }
+    }
+}
+
+/// one input image, a zip upload contributes one per file
+pub struct SourceImage {
+    /// relative path from the upload filename or zip entry
+    pub path: String,
+    /// format of the upload
+    pub target_img_type: ImageFormat,
+    blob: Bytes,
+    /// dimensions and color type, none when the header can't be read
+    header: Option<((u32, u32), ColorType)>,
+}
+
+impl SourceImage {
+    fn new(path: Option<&str>, target_img_type: ImageFormat, blob: Bytes) -> SourceImage {
+        let header = inspect::header(&blob).ok();
+
+        SourceImage {
+            path: filename::sanitize(path.unwrap_or("image")),
+            target_img_type,
+            blob,
+            header,
+        }
+    }
+
+    /// reject a broken single upload before anything is budgeted or decoded
+    fn check(&self) -> Result<()> {
+        if self.header.is_none() {
+            inspect::header(&self.blob)?;
+        }
+        Ok(())
+    }
+
+    /// folder of `path`, empty at the top level
+    pub fn dir(&self) -> &str {
+        self.path.rfind('/').map_or("", |i| &self.path[..i])
+    }
+
+    /// filename without extension
+    pub fn stem(&self) -> &str {
+        Path::new(&self.path)
+            .file_stem()
+            .and_then(|n| n.to_str())
+            .filter(|n| !n.is_empty())
+            .unwrap_or("image")
+    }
+
+    /// from the header
+    pub fn dimensions(&self) -> Option<(u32, u32)> {
+        self.header.map(|(size, _)| size)
+    }
+
+    /// from the header
+    pub fn has_alpha(&self) -> bool {
+        self.header.is_some_and(|(_, color)| color.has_alpha())
+    }
+
+    /// bytes held while this image is resized to `sizes`, see [`budget::estimate`]
+    pub fn memory_estimate(&self, sizes: &[Size]) -> u64 {
+        match self.header {
+            Some(((width, height), color)) => budget::estimate(
+                (width, height),
+                color,
+                sizes.iter().map(|ele| ele.target((width, height)).size()),
+            ),
+            // the decode fails right away
+            None => self.blob.len() as u64,
+        }
+    }
+
+    /// decoded when its turn comes, batch images one at a time, which bounds memory
+    pub async fn image(&self) -> Result<Arc<DynamicImage>> {
+        Ok(Arc::new(decode(self.blob.clone()).await?))
+    }
+}
 
 pub struct ImageResizeParams {
-    pub image: DynamicImage,
+    pub images: Vec<SourceImage>,
+    /// several blobs or a zip, failed images are reported instead of failing the request
+    pub batch: bool,
     pub width: u32,
     pub height: u32,
-    pub target_img_type: image::ImageFormat,
     pub sizes: Vec<Size>,
+    /// palette size, when set the palette is returned alongside the resized images
+    pub palette: Option<usize>,
+    /// derive smaller sizes from larger outputs instead of the full source
+    pub cascade: bool,
+    /// response body shape, see `ResponseFormat::from_name`
+    pub response: Option<String>,
+    pub filename_template: FilenameTemplate,
+    /// zip entries only
+    pub compression: ZipCompression,
+    /// posted when a job finishes, only used by /api/jobs
+    pub callback_url: Option<String>,
+    /// output format pinned by the client, negotiated from `accept` otherwise
+    pub format: Option<ImageFormat>,
+    /// jpeg and avif quality 1-100, the encoder default when missing
+    pub quality: Option<u8>,
+    pub accept: Option<String>,
+}
+
+/// json body of the resize endpoints, the image comes from `source_url` or `image`
+#[derive(Object)]
+#[oai(deny_unknown_fields)]
+pub struct ResizeJsonBody {
+    /// public http(s) url of the image
+    source_url: Option<String>,
+    /// base64 or a data url
+    image: Option<String>,
+    file_name: Option<String>,
+    /// name of a saved preset, the fields sent here override it
+    preset: Option<String>,
+    sizes: Option<Vec<Size>>,
+    /// declared size, rejected when it differs from the decoded one
+    #[oai(default)]
+    width: u32,
+    #[oai(default)]
+    height: u32,
+    /// colors in the palette returned alongside the images
+    palette: Option<usize>,
+    /// derive smaller sizes from larger outputs
+    cascade: Option<bool>,
+    /// zip, tar, multipart, json or image, from Accept when missing
+    response: Option<String>,
+    /// e.g. `{name}@{w}x{h}.{format}`
+    filename_template: Option<String>,
+    /// zip entries: stored, deflate or zstd
+    compression: Option<String>,
+    compression_level: Option<i64>,
+    /// posted when a job finishes, /api/jobs only
+    callback_url: Option<String>,
+    /// png, jpeg, webp or avif, negotiated from Accept when missing
+    format: Option<String>,
+    /// jpeg and avif quality 1-100
+    quality: Option<u8>,
+}
+
+/// multipart body of the resize endpoints
+#[derive(Multipart)]
+#[oai(deny_unknown_fields)]
+pub struct ResizeForm {
+    /// images, a zip of images is expanded into a batch
+    blob: Vec<Upload>,
+    preset: Option<String>,
+    sizes: Option<JsonField<Vec<Size>>>,
+    width: Option<u32>,
+    height: Option<u32>,
+    palette: Option<usize>,
+    cascade: Option<bool>,
+    response: Option<String>,
+    filename_template: Option<String>,
+    compression: Option<String>,
+    compression_level: Option<i64>,
+    callback_url: Option<String>,
+    format: Option<String>,
+    quality: Option<u8>,
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Object, Debug)]
+#[oai(deny_unknown_fields)]
 pub struct Size {
+    /// output side over source side, up to 8, left out for a box
+    #[oai(default)]
     pub scale: f32,
+    /// upscale with the ai model, costs a credit
+    #[oai(default)]
     pub use_ai: bool,
+    /// box to fit into instead of a scale, a missing side follows the aspect ratio
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    /// contain, cover or fill the box, contain by default
+    pub fit: Option<String>,
 }
 
-impl ImageResizeParams {
-    pub fn validate(&self) -> bool {
-        if self.sizes.is_empty() {
-            return false;
+impl Size {
+    /// fitted into a box rather than scaled
+    pub fn is_box(&self) -> bool {
+        self.width.is_some() || self.height.is_some()
+    }
+
+    /// `validate` rejects a fit that doesn't parse
+    fn fit_mode(&self) -> Fit {
+        self.fit
+            .as_deref()
+            .and_then(Fit::from_name)
+            .unwrap_or(Fit::Contain)
+    }
+
+    /// output size and crop for a `source` sized image
+    pub fn target(&self, source: (u32, u32)) -> Target {
+        if self.is_box() {
+            Target::fit(source, self.width, self.height, self.fit_mode())
+        } else {
+            Target::scaled(source, self.scale)
         }
+    }
 
-        for ele in &self.sizes {
-            if ele.scale == 0f32 {
-                return false;
-            }
+    /// output side over source side, the box output's width over the source's for a box
+    pub fn scale_of(&self, source: (u32, u32)) -> f32 {
+        if self.is_box() {
+            self.target(source).width as f32 / source.0 as f32
+        } else {
+            self.scale
+        }
+    }
+}
+
+/// options of a request as sent, before a preset fills the gaps
+#[derive(Default)]
+struct RequestOptions {
+    preset: Option<String>,
+    sizes: Option<Vec<Size>>,
+    width: u32,
+    height: u32,
+    palette: Option<usize>,
+    cascade: Option<bool>,
+    response: Option<String>,
+    filename_template: Option<String>,
+    compression: Option<String>,
+    compression_level: Option<i64>,
+    callback_url: Option<String>,
+    format: Option<String>,
+    quality: Option<u8>,
+}
+
+impl RequestOptions {
+    /// fields left out of the request come from the preset
+    fn fill(&mut self, preset: &PresetOptions) {
+        if self.sizes.is_none() {
+            self.sizes = Some(
+                preset
+                    .sizes
+                    .iter()
+                    .map(|s| Size {
+                        scale: s.scale,
+                        use_ai: s.use_ai,
+                        width: s.width,
+                        height: s.height,
+                        fit: s.fit.clone(),
+                    })
+                    .collect(),
+            );
         }
 
-        true
+        self.palette = self.palette.or(preset.palette);
+        self.cascade = self.cascade.or(preset.cascade);
+        self.response = self.response.take().or_else(|| preset.response.clone());
+        self.filename_template = self
+            .filename_template
+            .take()
+            .or_else(|| preset.filename_template.clone());
+        self.compression = self
+            .compression
+            .take()
+            .or_else(|| preset.compression.clone());
+        self.compression_level = self.compression_level.or(preset.compression_level);
+        self.format = self.format.take().or_else(|| preset.format.clone());
+        self.quality = self.quality.or(preset.quality);
     }
 
-    pub async fn from_multipart(mut multipart: Multipart) -> Result<ImageResizeParams> {
-        let mut image = Option::None;
+    /// presets belong to a user, `owner` is the login of the request
+    async fn load_preset(&mut self, owner: Option<&str>) -> Result<()> {
+        let Some(name) = &self.preset else {
+            return Ok(());
+        };
 
-        let mut target_img_type = image::ImageFormat::Png;
-        let mut sizes = vec![];
-        let mut width = 0;
-        let mut height = 0;
+        let owner =
+            owner.ok_or_else(|| AppError::new(ErrorCode::Unauthorized, "presets need a login"))?;
+        let preset = preset::get(owner, name).await?.ok_or_else(|| {
+            AppError::invalid_fields(vec![FieldError {
+                field: "preset".to_string(),
+                message: format!("no preset named {}", name),
+            }])
+        })?;
 
-        while let Ok(Some(field)) = multipart.next_field().await {
-            let name = field.name();
+        self.fill(&preset.options);
 
-            if name.is_none() {
-                continue;
+        Ok(())
+    }
+
+    fn into_params(self, images: Vec<SourceImage>, batch: bool) -> Result<ImageResizeParams> {
+        let filename_template = match &self.filename_template {
+            Some(template) => FilenameTemplate::parse(template)?,
+            None => FilenameTemplate::default(),
+        };
+
+        let compression = match &self.compression {
+            Some(method) => ZipCompression::parse(method, self.compression_level)?,
+            None => ZipCompression::default(),
+        };
+
+        let format = match &self.format {
+            Some(name) => Some(parse_format(name)?),
+            None => None,
+        };
+
+        Ok(ImageResizeParams {
+            images,
+            batch,
+            sizes: self.sizes.unwrap_or_default(),
+            width: self.width,
+            height: self.height,
+            palette: self.palette,
+            cascade: self.cascade.unwrap_or(false),
+            response: self.response,
+            filename_template,
+            compression,
+            callback_url: self.callback_url,
+            format,
+            quality: self.quality,
+            accept: None,
+        })
+    }
+}
+
+/// body of the resize endpoints as a `ResizeForm` or `ResizeJsonBody`, kept raw since
+/// jobs store it and parse it later
+pub struct ResizeBody(pub Body);
+
+impl<'a> ApiExtractor<'a> for ResizeBody {
+    const TYPES: &'static [ApiExtractorType] = &[ApiExtractorType::RequestObject];
+
+    type ParamType = ();
+    type ParamRawType = ();
+
+    fn register(registry: &mut Registry) {
+        <ResizeForm as Payload>::register(registry);
+        <Json<ResizeJsonBody> as Payload>::register(registry);
+    }
+
+    fn request_meta() -> Option<MetaRequest> {
+        Some(MetaRequest {
+            description: None,
+            content: vec![
+                MetaMediaType {
+                    content_type: ResizeForm::CONTENT_TYPE,
+                    schema: ResizeForm::schema_ref(),
+                },
+                MetaMediaType {
+                    content_type: Json::<ResizeJsonBody>::CONTENT_TYPE,
+                    schema: Json::<ResizeJsonBody>::schema_ref(),
+                },
+            ],
+            required: true,
+        })
+    }
+
+    async fn from_request(
+        _request: &'a Request,
+        body: &mut RequestBody,
+        _param_opts: ExtractParamOptions<Self::ParamType>,
+    ) -> poem::Result<Self> {
+        Ok(ResizeBody(body.take()?))
+    }
+}
+
+impl ImageResizeParams {
+    /// bytes the largest image holds while it is resized, batch images run one at a time
+    /// next to the outputs of the image before that the writer still holds
+    pub fn memory_estimate(&self) -> u64 {
+        let image = self
+            .images
+            .iter()
+            .map(|source| source.memory_estimate(&self.sizes))
+            .max()
+            .unwrap_or(0);
+        if !self.batch {
+            return image;
+        }
+
+        let output = self
+            .images
+            .iter()
+            .filter_map(|source| source.header)
+            .flat_map(|(size, color)| {
+                self.sizes
+                    .iter()
+                    .map(move |ele| budget::encoded(ele.target(size).size(), color))
+            })
+            .max()
+            .unwrap_or(0);
+
+        image + WRITER_BACKLOG as u64 * output
+    }
+
+    /// content type of a single image response
+    pub fn target_img_type(&self) -> ImageFormat {
+        self.images
+            .first()
+            .map_or(ImageFormat::Png, |s| self.output_format(s, s.has_alpha()))
+    }
+
+    /// format the outputs of `source` are encoded in
+    pub fn output_format(&self, source: &SourceImage, has_alpha: bool) -> ImageFormat {
+        self.format.unwrap_or_else(|| {
+            negotiate::image_format(self.accept.as_deref(), has_alpha, source.target_img_type)
+        })
+    }
+
+    /// whether the response depends on the Accept header
+    pub fn is_negotiated(&self) -> bool {
+        self.format.is_none()
+    }
+
+    /// sha256 of every source with its path and of the options that shape the output,
+    /// equal requests get equal digests whatever the field order or Accept wording
+    pub async fn digest(&self) -> Result<String> {
+        let options = json!({
+            "sizes": self
+                .sizes
+                .iter()
+                .map(|s| (s.scale, s.use_ai, s.width, s.height, &s.fit))
+                .collect::<Vec<_>>(),
+            "palette": self.palette,
+            "cascade": self.cascade,
+            "filename_template": self.filename_template.as_str(),
+            "compression": format!("{:?}", self.compression),
+            "quality": self.quality,
+            "format": match self.format {
+                Some(format) => vec![format],
+                None => negotiate::accepted_formats(self.accept.as_deref()),
             }
+            .iter()
+            .map(|f| f.extensions_str()[0])
+            .collect::<Vec<_>>(),
+        });
 
-            let name = name.unwrap();
-
-            match name {
-                "blob" => {
-                    let content_type = field.content_type();
-                    if let Some(content_type) = content_type {
-                        let content_type = content_type.to_string();
-                        let i = content_type.find("/");
-                        if let Some(i) = i {
-                            let image_type = image::ImageFormat::from_extension(
-                                content_type.to_string().split_at(i + 1).1,
-                            );
-                            if let Some(image_type) = image_type {
-                                target_img_type = image_type;
-                            }
-                        }
-                    }
+        let sources: Vec<(String, Bytes)> = self
+            .images
+            .iter()
+            .map(|s| (s.path.clone(), s.blob.clone()))
+            .collect();
 
-                    let b = field.bytes().await;
+        pool::run(move || {
+            let mut hasher = Sha256::new();
+            for (path, blob) in &sources {
+                hasher.update(path.as_bytes());
+                hasher.update(b"\0");
+                hasher.update(Sha256::digest(blob));
+            }
+            hasher.update(options.to_string().as_bytes());
 
-                    if b.is_err() {
-                        return Err(Error::msg("upload image is empty"));
-                    }
+            format!("{:x}", hasher.finalize())
+        })
+        .await
+    }
 
-                    let blob = b.unwrap();
-                    let cursor = Cursor::new(blob);
-                    let pic = ImageReader::new(cursor).with_guessed_format();
+    /// every field is checked, all failures are reported together
+    pub fn validate(&self, tier: Tier) -> AppResult<()> {
+        let mut errors = vec![];
+        let mut fail = |field: String, message: String| errors.push(FieldError { field, message });
 
-                    if pic.is_err() {
-                        return Err(Error::msg("upload image format is unkown"));
-                    }
+        if self.images.len() > MAX_BATCH_IMAGES {
+            fail(
+                "blob".to_string(),
+                format!("at most {} images", MAX_BATCH_IMAGES),
+            );
+        }
 
-                    let pic = pic.unwrap();
-                    let format = pic.format();
-                    if format.is_none() {
-                        return Err(Error::msg("upload image format is unkown"));
-                    }
+        if self.sizes.is_empty() {
+            fail(
+                "sizes".to_string(),
+                "at least one size is required".to_string(),
+            );
+        } else if self.sizes.len() > tier.max_sizes() {
+            fail(
+                "sizes".to_string(),
+                match tier {
+                    Tier::Free => format!("at most {} sizes without a login", tier.max_sizes()),
+                    Tier::User => format!("at most {} sizes", tier.max_sizes()),
+                },
+            );
+        }
 
-                    image = Some(pic.decode()?);
+        // batch images are not checked, one too large fails on its own
+        let dimensions = match self.images.as_slice() {
+            [image] => image.dimensions(),
+            _ => None,
+        };
+
+        for (i, ele) in self.sizes.iter().enumerate() {
+            if ele.is_box() {
+                if ele.scale != 0f32 {
+                    fail(
+                        format!("sizes[{}].scale", i),
+                        "is left out with width or height".to_string(),
+                    );
                 }
-                "sizes" => {
-                    let text = field.text().await;
-                    if text.is_ok() {
-                        let ss = serde_json::from_str::<Vec<Size>>(&text.unwrap());
-
-                        if ss.is_ok() {
-                            sizes = ss.unwrap();
-                        }
-                    }
+                if ele.use_ai {
+                    fail(
+                        format!("sizes[{}].use_ai", i),
+                        "is not supported with width or height".to_string(),
+                    );
                 }
-                "width" => {
-                    let text = field.text().await;
-                    if text.is_ok() {
-                        let w = text.unwrap().parse::<u32>();
-                        if w.is_ok() {
-                            width = w.unwrap();
-                        }
+                for (field, side) in [("width", ele.width), ("height", ele.height)] {
+                    if side.is_some_and(|side| side == 0 || side > MAX_OUTPUT_SIDE) {
+                        fail(
+                            format!("sizes[{}].{}", i, field),
+                            format!("must be between 1 and {}", MAX_OUTPUT_SIDE),
+                        );
                     }
                 }
-                "height" => {
-                    let text = field.text().await;
-                    if text.is_ok() {
-                        let h = text.unwrap().parse::<u32>();
-                        if h.is_ok() {
-                            height = h.unwrap();
-                        }
-                    }
+                if ele
+                    .fit
+                    .as_deref()
+                    .is_some_and(|f| Fit::from_name(f).is_none())
+                {
+                    fail(
+                        format!("sizes[{}].fit", i),
+                        "expected contain, cover or fill".to_string(),
+                    );
+                }
+            } else if ele.fit.is_some() {
+                fail(
+                    format!("sizes[{}].fit", i),
+                    "only applies with width or height".to_string(),
+                );
+            } else if !ele.scale.is_finite() {
+                fail(
+                    format!("sizes[{}].scale", i),
+                    "must be a number".to_string(),
+                );
+            } else if ele.scale <= 0f32 {
+                fail(
+                    format!("sizes[{}].scale", i),
+                    "must be greater than 0, or give width or height".to_string(),
+                );
+            } else if ele.scale > MAX_SCALE {
+                fail(
+                    format!("sizes[{}].scale", i),
+                    format!("must be at most {}", MAX_SCALE),
+                );
+            }
+
+            // a one sided box follows the aspect ratio, so it is checked like a scale
+            let in_range = if ele.is_box() {
+                [ele.width, ele.height]
+                    .into_iter()
+                    .flatten()
+                    .all(|side| (1..=MAX_OUTPUT_SIDE).contains(&side))
+            } else {
+                ele.scale.is_finite() && ele.scale > 0f32 && ele.scale <= MAX_SCALE
+            };
+            if let Some((width, height)) = dimensions.filter(|_| in_range) {
+                let (w, h) = ele.target((width, height)).size();
+                if w > MAX_OUTPUT_SIDE || h > MAX_OUTPUT_SIDE {
+                    let field = if ele.is_box() { "width" } else { "scale" };
+                    fail(
+                        format!("sizes[{}].{}", i, field),
+                        format!("output {}x{} is larger than {}", w, h, MAX_OUTPUT_SIDE),
+                    );
                 }
-                &_ => continue,
             }
+
+            if let Some(j) = self.sizes[..i].iter().position(|other| {
+                other.scale == ele.scale
+                    && other.use_ai == ele.use_ai
+                    && (other.width, other.height) == (ele.width, ele.height)
+                    && other.fit_mode() == ele.fit_mode()
+            }) {
+                fail(format!("sizes[{}]", i), format!("duplicates sizes[{}]", j));
+            }
+        }
+
+        if (self.width == 0) != (self.height == 0) {
+            let missing = if self.width == 0 { "width" } else { "height" };
+            fail(
+                missing.to_string(),
+                "width and height are declared together".to_string(),
+            );
+        } else if self.width != 0 {
+            match dimensions {
+                Some((width, height)) if (self.width, self.height) != (width, height) => fail(
+                    "width".to_string(),
+                    format!(
+                        "declared {}x{} but the image is {}x{}",
+                        self.width, self.height, width, height
+                    ),
+                ),
+                Some(_) => {}
+                None => fail(
+                    "width".to_string(),
+                    "only declared for a single image".to_string(),
+                ),
+            }
+        }
+
+        if let Err(e) = self.check_palette() {
+            fail("palette".to_string(), e);
+        }
+
+        if matches!(self.quality, Some(q) if q == 0 || q > 100) {
+            fail(
+                "quality".to_string(),
+                "must be between 1 and 100".to_string(),
+            );
         }
 
-        if image.is_none() {
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(AppError::invalid_fields(errors))
+        }
+    }
+
+    pub fn validate_palette(&self) -> AppResult<()> {
+        self.check_palette().map_err(|message| {
+            AppError::invalid_fields(vec![FieldError {
+                field: "palette".to_string(),
+                message,
+            }])
+        })
+    }
+
+    fn check_palette(&self) -> Result<(), String> {
+        match self.palette {
+            Some(n) if n == 0 || n > palette::MAX_PALETTE_SIZE => Err(format!(
+                "must be between 1 and {}",
+                palette::MAX_PALETTE_SIZE
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    /// a preset is checked like the options of a request, without an image
+    pub fn validate_preset(options: &PresetOptions) -> AppResult<()> {
+        let mut errors = vec![];
+        let mut fail = |field: &str, message: String| {
+            errors.push(FieldError {
+                field: field.to_string(),
+                message,
+            })
+        };
+
+        // parsed before the other checks, as in a request
+        if let Some(Err(e)) = options.format.as_deref().map(parse_format) {
+            fail("format", e.to_string());
+        }
+        if let Some(Err(e)) = options
+            .filename_template
+            .as_deref()
+            .map(FilenameTemplate::parse)
+        {
+            fail("filename_template", e.to_string());
+        }
+        if let Some(Err(e)) = options
+            .compression
+            .as_deref()
+            .map(|method| ZipCompression::parse(method, options.compression_level))
+        {
+            fail("compression", e.to_string());
+        }
+
+        if !errors.is_empty() {
+            return Err(AppError::invalid_fields(errors));
+        }
+
+        let mut request = RequestOptions::default();
+        request.fill(options);
+
+        let params = request
+            .into_params(vec![], false)
+            .map_err(|e| AppError::from_anyhow(&e, ErrorCode::InvalidParams))?;
+
+        params.validate(Tier::User)
+    }
+
+    /// multipart form or json body, picked by content type, `owner` is the login that
+    /// `preset` is looked up for
+    pub async fn from_request(
+        req: &Request,
+        body: Body,
+        owner: Option<&str>,
+    ) -> Result<ImageResizeParams> {
+        let mut params = if is_json(req) {
+            let mut body = RequestBody::new(Body::from_bytes(read_json(req, body).await?));
+            let Json(body) =
+                <Json<ResizeJsonBody> as ParsePayload>::from_request(req, &mut body).await?;
+            Self::from_json(body, owner).await?
+        } else {
+            let mut body = RequestBody::new(body);
+            let form = <ResizeForm as ParsePayload>::from_request(req, &mut body).await?;
+            Self::from_form(form, owner).await?
+        };
+
+        params.accept = req.header(header::ACCEPT).map(|s| s.to_string());
+
+        Ok(params)
+    }
+
+    pub async fn from_json(body: ResizeJsonBody, owner: Option<&str>) -> Result<ImageResizeParams> {
+        let policy = fetch::get_policy();
+
+        let (blob, file_name) = match (&body.source_url, &body.image) {
+            (Some(url), None) => {
+                let blob = fetch::fetch(url, policy).await?;
+                let path_name = Url::parse(url)?
+                    .path_segments()
+                    .and_then(|mut s| s.next_back())
+                    .map(|s| s.to_string());
+                (blob, body.file_name.clone().or(path_name))
+            }
+            (None, Some(image)) => {
+                let blob = decode_base64(image)?;
+                if blob.len() > policy.max_bytes {
+                    return Err(too_large("upload image is too large"));
+                }
+                (blob, body.file_name.clone())
+            }
+            _ => return Err(Error::msg("one of source_url or image is required")),
+        };
+
+        let target_img_type = image::guess_format(&blob).map_err(|_| unknown_format())?;
+        let image = SourceImage::new(file_name.as_deref(), target_img_type, blob);
+        image.check()?;
+
+        let mut options = RequestOptions {
+            preset: body.preset,
+            sizes: body.sizes,
+            width: body.width,
+            height: body.height,
+            palette: body.palette,
+            cascade: body.cascade,
+            response: body.response,
+            filename_template: body.filename_template,
+            compression: body.compression,
+            compression_level: body.compression_level,
+            callback_url: body.callback_url,
+            format: body.format,
+            quality: body.quality,
+        };
+        options.load_preset(owner).await?;
+
+        options.into_params(vec![image], false)
+    }
+
+    pub async fn from_form(form: ResizeForm, owner: Option<&str>) -> Result<ImageResizeParams> {
+        let mut images = vec![];
+        let mut batch = false;
+
+        for upload in form.blob {
+            let file_name = upload.file_name().map(|n| n.to_string());
+            let target_img_type = upload
+                .content_type()
+                .and_then(|c| c.split_once('/'))
+                .and_then(|(_, ext)| ImageFormat::from_extension(ext));
+
+            let blob = Bytes::from(upload.into_vec().await?);
+
+            if blob.starts_with(ZIP_MAGIC) {
+                batch = true;
+                images.extend(unzip(blob).await?);
+            } else {
+                // sniff uploads sent without a usable content type
+                let target_img_type = target_img_type
+                    .or_else(|| image::guess_format(&blob).ok())
+                    .unwrap_or(ImageFormat::Png);
+                images.push(SourceImage::new(
+                    file_name.as_deref(),
+                    target_img_type,
+                    blob,
+                ));
+            }
+        }
+
+        if images.is_empty() {
             return Err(Error::msg("upload image is empty"));
         }
 
-        Ok(ImageResizeParams {
-            image: image.unwrap(),
-            target_img_type,
-            sizes,
+        batch |= images.len() > 1;
+        if !batch {
+            images[0].check()?;
+        }
+
+        let mut options = RequestOptions {
+            preset: form.preset,
+            sizes: form.sizes.map(|JsonField(sizes)| sizes),
+            width: form.width.unwrap_or(0),
+            height: form.height.unwrap_or(0),
+            palette: form.palette,
+            cascade: form.cascade,
+            response: form.response,
+            filename_template: form.filename_template,
+            compression: form.compression,
+            compression_level: form.compression_level,
+            callback_url: form.callback_url,
+            format: form.format,
+            quality: form.quality,
+        };
+        options.load_preset(owner).await?;
+
+        options.into_params(images, batch)
+    }
+}
+
+/// the json body, refused as soon as it is larger than an inline image can be
+async fn read_json(req: &Request, body: Body) -> Result<Bytes> {
+    // base64 grows the image by a third, the other fields are small
+    read_limited(
+        req,
+        body,
+        fetch::get_policy().max_bytes / 3 * 4 + JSON_FIELDS_BYTES,
+    )
+    .await
+}
+
+/// the whole body of a request buffered before parsing, with the limit its type gets in
+/// `from_request`
+pub async fn read_body(req: &Request, body: Body) -> Result<Bytes> {
+    if is_json(req) {
+        return read_json(req, body).await;
+    }
+    read_limited(req, body, MAX_ZIP_BYTES as usize + JSON_FIELDS_BYTES).await
+}
+
+fn is_json(req: &Request) -> bool {
+    req.content_type()
+        .is_some_and(|c| c.starts_with("application/json"))
+}
+
+async fn read_limited(req: &Request, body: Body, limit: usize) -> Result<Bytes> {
+    let declared = req
+        .header(header::CONTENT_LENGTH)
+        .and_then(|len| len.parse::<usize>().ok());
+    if declared.is_some_and(|len| len > limit) {
+        return Err(too_large("request body is too large"));
+    }
+
+    match body.into_bytes_limit(limit).await {
+        Ok(bytes) => Ok(bytes),
+        Err(ReadBodyError::PayloadTooLarge) => Err(too_large("request body is too large")),
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn too_large(msg: &str) -> Error {
+    AppError::new(ErrorCode::PayloadTooLarge, msg).into()
+}
+
+fn unknown_format() -> Error {
+    AppError::new(
+        ErrorCode::UnsupportedFormat,
+        "upload image format is unkown",
+    )
+    .into()
+}
+
+fn parse_format(name: &str) -> Result<ImageFormat> {
+    format::from_name(name).ok_or_else(|| Error::msg("format not support"))
+}
+
+/// decode on the resize pool
+async fn decode(blob: Bytes) -> Result<DynamicImage> {
+    let pic = ImageReader::new(Cursor::new(blob)).with_guessed_format();
+
+    if pic.is_err() {
+        return Err(unknown_format());
+    }
+
+    let pic = pic.unwrap();
+    if pic.format().is_none() {
+        return Err(unknown_format());
+    }
+
+    let image = pool::run(move || pic.decode()).await??;
+
+    Ok(image)
+}
+
+/// every file of a zip upload with its folder, hidden files and macOS metadata are skipped
+async fn unzip(blob: Bytes) -> Result<Vec<SourceImage>> {
+    pool::run(move || {
+        let mut archive = ZipArchive::new(Cursor::new(blob))?;
+        let mut images = vec![];
+        let mut total = 0;
+
+        for i in 0..archive.len() {
+            let mut file = archive.by_index(i)?;
+            if !file.is_file() {
+                continue;
+            }
+
+            let path = match file.enclosed_name() {
+                Some(path) => path.to_string_lossy().replace('\\', "/"),
+                None => continue,
+            };
+            if path
+                .split('/')
+                .any(|p| p.starts_with('.') || p == "__MACOSX")
+            {
+                continue;
+            }
+
+            if images.len() >= MAX_BATCH_IMAGES {
+                return Err(too_large("zip has too many images"));
+            }
+
+            // the declared size can lie, count what actually inflates
+            let mut buf = vec![];
+            (&mut file)
+                .take(MAX_ZIP_BYTES - total + 1)
+                .read_to_end(&mut buf)?;
+            total += buf.len() as u64;
+            if total > MAX_ZIP_BYTES {
+                return Err(too_large("zip is too large"));
+            }
+
+            let blob = Bytes::from(buf);
+            let target_img_type = image::guess_format(&blob).unwrap_or(ImageFormat::Png);
+            images.push(SourceImage::new(Some(&path), target_img_type, blob));
+        }
+
+        Ok(images)
+    })
+    .await?
+}
+
+/// plain base64 or `data:<mime>;base64,<data>`
+fn decode_base64(image: &str) -> Result<Bytes> {
+    let data = match image.strip_prefix("data:") {
+        Some(rest) => {
+            let (meta, data) = rest
+                .split_once(',')
+                .ok_or_else(|| Error::msg("data url has no data"))?;
+            if !meta.ends_with(";base64") {
+                return Err(Error::msg("data url must be base64 encoded"));
+            }
+            data
+        }
+        None => image,
+    };
+
+    let data = data.trim();
+    let blob = BASE64_STANDARD
+        .decode(data)
+        .or_else(|_| BASE64_URL_SAFE.decode(data))?;
+
+    Ok(Bytes::from(blob))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scale(scale: f32) -> Size {
+        Size {
+            scale,
+            use_ai: false,
+            width: None,
+            height: None,
+            fit: None,
+        }
+    }
+
+    fn boxed(width: Option<u32>, height: Option<u32>, fit: Option<&str>) -> Size {
+        Size {
+            scale: 0f32,
+            use_ai: false,
             width,
             height,
-        })
+            fit: fit.map(str::to_string),
+        }
+    }
+
+    fn params(images: Vec<SourceImage>, sizes: Vec<Size>) -> ImageResizeParams {
+        ImageResizeParams {
+            images,
+            batch: false,
+            width: 0,
+            height: 0,
+            sizes,
+            palette: None,
+            cascade: false,
+            response: None,
+            filename_template: FilenameTemplate::default(),
+            compression: ZipCompression::default(),
+            callback_url: None,
+            format: None,
+            quality: None,
+            accept: None,
+        }
+    }
+
+    fn png(width: u32, height: u32) -> SourceImage {
+        let mut blob = vec![];
+        DynamicImage::new_rgb8(width, height)
+            .write_to(&mut Cursor::new(&mut blob), ImageFormat::Png)
+            .unwrap();
+        SourceImage::new(Some("a.png"), ImageFormat::Png, Bytes::from(blob))
+    }
+
+    /// `details.fields` of a failed validation
+    fn failed_fields(params: &ImageResizeParams, tier: Tier) -> Vec<String> {
+        let e = params.validate(tier).unwrap_err();
+        assert_eq!(e.code, ErrorCode::InvalidParams);
+        e.details.unwrap()["fields"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|f| f["field"].as_str().unwrap().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn every_invalid_size_is_reported_with_its_path() {
+        let params = params(
+            vec![png(100, 400)],
+            vec![
+                scale(2f32),
+                scale(0f32),
+                scale(9f32),
+                boxed(Some(0), None, None),
+                boxed(Some(10), Some(10), Some("stretch")),
+                Size {
+                    fit: Some("cover".to_string()),
+                    ..scale(1f32)
+                },
+                Size {
+                    use_ai: true,
+                    ..boxed(None, Some(10), None)
+                },
+                // 100x400 fitted to 16000 wide is 64000 high
+                boxed(Some(16000), None, None),
+                scale(2f32),
+            ],
+        );
+
+        assert_eq!(
+            failed_fields(&params, Tier::User),
+            [
+                "sizes[1].scale",
+                "sizes[2].scale",
+                "sizes[3].width",
+                "sizes[4].fit",
+                "sizes[5].fit",
+                "sizes[6].use_ai",
+                "sizes[7].width",
+                "sizes[8]",
+            ]
+        );
+    }
+
+    #[test]
+    fn valid_sizes_pass() {
+        let params = params(
+            vec![png(100, 400)],
+            vec![
+                scale(0.5),
+                scale(8f32),
+                boxed(Some(50), None, None),
+                boxed(Some(50), Some(50), Some("cover")),
+                boxed(Some(50), Some(50), Some("fill")),
+            ],
+        );
+
+        assert!(params.validate(Tier::Free).is_ok());
+    }
+
+    #[test]
+    fn size_count_depends_on_the_tier() {
+        // MAX_SIZES_FREE and MAX_SIZES_USER are not set in tests
+        assert_eq!(Tier::Free.max_sizes(), 8);
+        assert_eq!(Tier::User.max_sizes(), 32);
+
+        let sizes = |n: usize| (1..=n).map(|i| scale(i as f32 / 10f32)).collect();
+
+        assert!(params(vec![], sizes(8)).validate(Tier::Free).is_ok());
+        assert_eq!(
+            failed_fields(&params(vec![], sizes(9)), Tier::Free),
+            ["sizes"]
+        );
+        assert!(params(vec![], sizes(9)).validate(Tier::User).is_ok());
+        assert!(params(vec![], sizes(32)).validate(Tier::User).is_ok());
+        assert_eq!(
+            failed_fields(&params(vec![], sizes(33)), Tier::User),
+            ["sizes"]
+        );
+        assert_eq!(
+            failed_fields(&params(vec![], vec![]), Tier::User),
+            ["sizes"]
+        );
     }
 }
@@ -0,0 +1,95 @@
+use poem_openapi::{param::Path, payload::Json, ApiResponse, OpenApi};
+
+use crate::{
+    api::{
+        openapi::{ApiTags, TokenAuth},
+        params::resize_params::ImageResizeParams,
+    },
+    db::preset::{self, Preset, PresetOptions},
+    status_code::{AppError, AppResult, ErrorCode, FieldError, ResultExt},
+};
+
+const MAX_NAME_LEN: usize = 64;
+
+#[derive(ApiResponse)]
+enum DeleteResponse {
+    #[oai(status = 204)]
+    Deleted,
+}
+
+pub struct PresetsApi;
+
+#[OpenApi(tag = "ApiTags::Presets")]
+impl PresetsApi {
+    /// presets of the user, send `preset` with a resize request to use one
+    #[oai(path = "/presets", method = "get")]
+    async fn list(&self, auth: TokenAuth) -> AppResult<Json<Vec<Preset>>> {
+        let presets = preset::list(&auth.0.email)
+            .await
+            .app_err(ErrorCode::Internal)?;
+
+        Ok(Json(presets))
+    }
+
+    #[oai(path = "/presets/:name", method = "get")]
+    async fn get(&self, name: Path<String>, auth: TokenAuth) -> AppResult<Json<Preset>> {
+        let preset = preset::get(&auth.0.email, &name)
+            .await
+            .app_err(ErrorCode::Internal)?
+            .ok_or_else(not_found)?;
+
+        Ok(Json(preset))
+    }
+
+    /// create or replace, checked like the fields of a resize request
+    #[oai(path = "/presets/:name", method = "put")]
+    async fn put(
+        &self,
+        name: Path<String>,
+        options: Json<PresetOptions>,
+        auth: TokenAuth,
+    ) -> AppResult<Json<Preset>> {
+        check_name(&name)?;
+        ImageResizeParams::validate_preset(&options)?;
+
+        let preset = preset::put(&auth.0.email, &name, options.0)
+            .await
+            .app_err(ErrorCode::Internal)?;
+
+        Ok(Json(preset))
+    }
+
+    #[oai(path = "/presets/:name", method = "delete")]
+    async fn delete(&self, name: Path<String>, auth: TokenAuth) -> AppResult<DeleteResponse> {
+        if !preset::remove(&auth.0.email, &name)
+            .await
+            .app_err(ErrorCode::Internal)?
+        {
+            return Err(not_found());
+        }
+
+        Ok(DeleteResponse::Deleted)
+    }
+}
+
+fn not_found() -> AppError {
+    AppError::new(ErrorCode::NotFound, "preset not found")
+}
+
+/// names go in urls and requests, keep them plain
+fn check_name(name: &str) -> AppResult<()> {
+    let valid = !name.is_empty()
+        && name.len() <= MAX_NAME_LEN
+        && name
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_' || b == b'.');
+
+    if valid {
+        Ok(())
+    } else {
+        Err(AppError::invalid_fields(vec![FieldError {
+            field: "name".to_string(),
+            message: format!("1 to {} letters, digits, '-', '_' or '.'", MAX_NAME_LEN),
+        }]))
+    }
+}
@@ -1,178 +1,715 @@
-use std::{
-    borrow::Borrow,
-    io::{Read, Write},
-    path::Path,
+use std::{collections::HashMap, io::Cursor, iter, sync::Arc};
+
+use anyhow::{Context, Error, Result};
+use bytes::Bytes;
+use futures_util::future::BoxFuture;
+use image::{DynamicImage, ImageFormat, ImageReader};
+use image_resize_core::{
+    algorithm,
+    filename::{self, FileVars, UniqueNames},
+    format, Frame, Target,
+};
+use poem::{http::header, Body, Request};
+use poem_openapi::{ApiResponse, OpenApi};
+use serde_json::json;
+use tokio::{
+    sync::{
+        mpsc::{self, Receiver, Sender},
+        oneshot, OnceCell, Semaphore,
+    },
+    task::{JoinHandle, JoinSet},
 };
-
-use anyhow::Result;
-use poem::{handler, http::StatusCode, web::Multipart, Body, Response};
 use tracing::{error, warn};
-use uuid::Uuid;
-use zip::write::SimpleFileOptions;
 
 use crate::{
-    api::{gen_known_err_response, params::resize_params::ImageResizeParams},
-    core::{ai, algorithm, transform},
-    db::{file::upload_temp, user::update_credits},
+    api::{
+        cache::{self, Endpoint},
+        openapi::{ApiTags, TokenAuth},
+        params::resize_params::{
+            ImageResizeParams, ResizeBody, SourceImage, Tier, MAX_OUTPUT_SIDE,
+        },
+        result_cache::{self, AiCreditPolicy, Capture},
+        stream::{
+            self, Download, Entry, Failure, Output, ResizeMethod, ResponseFormat, MANIFEST_NAME,
+        },
+    },
+    core::{
+        ai,
+        budget::{self, Permit},
+        palette, pool,
+    },
+    db::{
+        file::upload_temp,
+        user::{update_credits, User},
+    },
     extractor::auth_user::AuthUser,
+    status_code::{AppError, AppResult, ErrorCode, ResultExt},
 };
 
-use super::params::resize_params::Size;
-
-#[handler]
-pub async fn resize_free(mut multipart: Multipart) -> Response {
-    let params = ImageResizeParams::from_multipart(multipart).await;
+/// finished outputs waiting for the archive writer
+const ENTRY_BUFFER: usize = 2;
+
+/// encoded outputs of the image before that the writer can still hold, queued or writing
+pub const WRITER_BACKLOG: usize = ENTRY_BUFFER + 1;
+
+#[derive(ApiResponse)]
+pub enum ResizeResponse {
+    /// zip, tar, multipart, json or the single image, see `response`
+    #[oai(status = 200)]
+    Ok(
+        Download,
+        #[oai(header = "ETag")] String,
+        #[oai(header = "Cache-Control")] String,
+        #[oai(header = "Vary")] Option<String>,
+    ),
+}
 
-    if params.is_err() {
-        error!("{:?}", params.err());
-        return Response::builder()
-            .status(StatusCode::BAD_REQUEST)
-            .body("params init fail");
-    }
+pub struct ResizeApi;
 
-    let params = params.unwrap();
+#[OpenApi(tag = "ApiTags::Resize")]
+impl ResizeApi {
+    /// resize with the algorithm only, no login needed
+    #[oai(path = "/resizefree", method = "post")]
+    async fn resize_free(&self, req: &Request, body: ResizeBody) -> AppResult<ResizeResponse> {
+        let params = ImageResizeParams::from_request(req, body.0, None)
+            .await
+            .app_err(ErrorCode::InvalidParams)?;
 
-    if !params.validate() {
-        return Response::builder()
-            .status(StatusCode::BAD_REQUEST)
-            .body("params validate fail");
-    }
+        params.validate(Tier::Free)?;
 
-    for ele in &params.sizes {
-        if ele.use_ai {
-            return Response::builder()
-                .status(StatusCode::BAD_REQUEST)
-                .body("resize free not support use_ai");
+        for ele in &params.sizes {
+            if ele.use_ai {
+                return Err(AppError::invalid_params("resize free not support use_ai"));
+            }
         }
-    }
 
-    let r = handle(&params, None).await;
+        let format = negotiate_format(req, &params)
+            .ok_or_else(|| AppError::invalid_params("response format not support"))?;
 
-    if let Err(e) = r {
-        error!("{:?}", e);
-        return Response::builder()
-            .status(StatusCode::INTERNAL_SERVER_ERROR)
-            .body(e.to_string());
+        handle(params, None, format)
+            .await
+            .app_err(ErrorCode::Internal)
     }
 
-    r.unwrap()
+    /// resize with ai sizes charged to the user's credits
+    #[oai(path = "/resize", method = "post")]
+    async fn resize(
+        &self,
+        req: &Request,
+        body: ResizeBody,
+        auth: TokenAuth,
+        user: AuthUser,
+    ) -> AppResult<ResizeResponse> {
+        let params = ImageResizeParams::from_request(req, body.0, Some(&auth.0.email))
+            .await
+            .app_err(ErrorCode::InvalidParams)?;
+
+        params.validate(Tier::User)?;
+
+        let format = negotiate_format(req, &params)
+            .ok_or_else(|| AppError::invalid_params("response format not support"))?;
+
+        handle(params, Some(user), format)
+            .await
+            .app_err(ErrorCode::Internal)
+    }
 }
 
-#[handler]
-pub async fn resize(mut multipart: Multipart, user: AuthUser) -> Response {
-    let params = ImageResizeParams::from_multipart(multipart).await;
+/// explicit `response` param wins over the Accept header, zip otherwise
+pub fn negotiate_format(req: &Request, params: &ImageResizeParams) -> Option<ResponseFormat> {
+    let outputs = (params.sizes.len() + params.palette.map_or(0, |_| 1)) * params.images.len();
 
-    if params.is_err() {
-        error!("{:?}", params.err());
-        return Response::builder()
-            .status(StatusCode::BAD_REQUEST)
-            .body("params init fail");
+    if let Some(name) = &params.response {
+        let format = ResponseFormat::from_name(name)?;
+        if format == ResponseFormat::Image && outputs != 1 {
+            return None;
+        }
+        return Some(format);
     }
 
-    let params = params.unwrap();
+    let format = req
+        .header(header::ACCEPT)
+        .and_then(ResponseFormat::from_accept);
 
-    if !params.validate() {
-        return Response::builder()
-            .status(StatusCode::BAD_REQUEST)
-            .body("params validate fail");
+    match format {
+        // browsers ask for image/* by default, several outputs still need an archive
+        Some(ResponseFormat::Image) if outputs != 1 => Some(ResponseFormat::Zip),
+        Some(format) => Some(format),
+        None => Some(ResponseFormat::Zip),
     }
+}
 
-    let r = handle(&params, Some(user)).await;
-
-    if let Err(e) = r {
-        error!("{:?}", e);
-        return Response::builder()
-            .status(StatusCode::INTERNAL_SERVER_ERROR)
-            .finish();
+async fn handle(
+    params: ImageResizeParams,
+    user: Option<AuthUser>,
+    format: ResponseFormat,
+) -> Result<ResizeResponse> {
+    let digest = params.digest().await?;
+    let etag = cache::etag(&format!("{}-{}", digest, format.name()));
+    // the image format or the response shape came from Accept
+    let negotiated = params.is_negotiated() || params.response.is_none();
+    let cache_control = Endpoint::Resize.cache_control().to_string();
+    let vary = negotiated.then(|| "Accept".to_string());
+
+    let cached = result_cache::get(&digest).await;
+    let free = cached.is_some() && result_cache::ai_credit_policy() == AiCreditPolicy::Free;
+
+    if !free {
+        check_credits(&params, user.as_ref().map(|u| &u.user))?;
     }
 
-    r.unwrap()
+    let content_type = format.content_type(params.target_img_type());
+    let compression = params.compression;
+
+    let body = match cached {
+        Some(entries) => {
+            let (writer, body) = stream::body_channel();
+            replay(entries, free, user.map(|u| u.user), move |outputs| {
+                stream::write_archive(&format, compression, outputs, writer)
+            });
+            body
+        }
+        None => {
+            // a replay holds no decoded pixels, only fresh work waits for the budget
+            let permit = budget::acquire(params.memory_estimate()).await?;
+
+            if format == ResponseFormat::Image {
+                let data = single_image(params, user.map(|u| u.user), permit, digest).await?;
+                Body::from_bytes(data)
+            } else {
+                let (writer, body) = stream::body_channel();
+                start(params, user.map(|u| u.user), permit, move |mut entries| {
+                    let mut capture = Capture::new(digest);
+                    let outputs = iter::from_fn(move || entries.blocking_recv())
+                        .inspect(|output| capture.record(output));
+                    stream::write_archive(&format, compression, outputs, writer)?;
+                    capture.store();
+                    Ok(())
+                })
+                .await?;
+                body
+            }
+        }
+    };
+
+    Ok(ResizeResponse::Ok(
+        Download { content_type, body },
+        etag,
+        cache_control,
+        vary,
+    ))
 }
-async fn handle(params: &ImageResizeParams, user: Option<AuthUser>) -> Result<Response> {
-    let temp_name = format!("{}.zip", Uuid::new_v4());
 
-    let path: &Path = std::path::Path::new(&temp_name);
-    let file = std::fs::File::create(path)?;
+/// the only output of an image response, finished before the status is sent so a failed
+/// encode is an error response rather than an empty 200
+async fn single_image(
+    params: ImageResizeParams,
+    user: Option<User>,
+    permit: Permit,
+    digest: String,
+) -> Result<Bytes> {
+    let (tx, rx) = oneshot::channel();
+
+    let task = start(params, user, permit, move |mut entries| {
+        let mut capture = Capture::new(digest);
+        let mut data = None;
+        while let Some(output) = entries.blocking_recv() {
+            capture.record(&output);
+            match output? {
+                Output::Entry(entry) => data = Some(entry.data),
+                // a batch of one reports its image failing, there is no manifest to hold it
+                Output::Failure(failure) => {
+                    return Err(AppError::new(ErrorCode::InvalidImage, failure.error).into())
+                }
+            }
+        }
+        capture.store();
+        let _ = tx.send(data);
+        Ok(())
+    })
+    .await?;
+
+    task.await??;
+    rx.await?
+        .ok_or_else(|| Error::msg("resize produced no image"))
+}
 
-    defer::defer! {
-        std::fs::remove_file(path).unwrap_or_else(|e| {
-            warn!("remove file error: {:?}", e);
+/// stream the outputs of an earlier identical request, ai outputs are charged again
+/// unless `free`
+fn replay<F>(entries: Vec<Entry>, free: bool, user: Option<User>, write: F)
+where
+    F: FnOnce(std::vec::IntoIter<Result<Output>>) -> Result<()> + Send + 'static,
+{
+    let entries: Vec<Result<Output>> = entries
+        .into_iter()
+        .map(|mut entry| {
+            if free {
+                entry.credits = 0;
+            }
+            Ok(Output::Entry(entry))
         })
+        .collect();
+    let credits: i64 = entries
+        .iter()
+        .map(|e| match e {
+            Ok(Output::Entry(entry)) => entry.credits,
+            _ => 0,
+        })
+        .sum();
+
+    tokio::spawn(async move {
+        let r = tokio::task::spawn_blocking(move || write(entries.into_iter())).await;
+
+        match r {
+            Ok(Ok(())) => {
+                if let Some(user) = user {
+                    if credits > 0 {
+                        if let Err(e) = update_credits(&user.email, -credits).await {
+                            error!("update credits error: {:?}", e);
+                        }
+                    }
+                }
+            }
+            Ok(Err(e)) => error!("{:?}", e),
+            Err(e) => error!("{:?}", e),
+        }
+    });
+}
+
+/// every ai output of every image needs a credit
+pub fn check_credits(params: &ImageResizeParams, user: Option<&User>) -> AppResult<()> {
+    let ai_sizes = params.sizes.iter().filter(|ele| ele.use_ai).count() * params.images.len();
+
+    match user {
+        Some(user) if ai_sizes > 0 && user.credit < ai_sizes as i64 => Err(AppError::new(
+            ErrorCode::InsufficientCredits,
+            "credits is not enough",
+        )
+        .with_details(json!({
+            "credits": user.credit,
+            "ai_sizes": ai_sizes,
+        }))),
+        _ => Ok(()),
     }
+}
 
-    let mut zip = zip::ZipWriter::new(&file);
+/// produce every output in the background, `write` gets them on a blocking thread
+///
+/// a single image fails here before anything is written, the returned task resolves
+/// to the ai outputs charged once everything is written and holds `permit` until then
+pub async fn start<F>(
+    params: ImageResizeParams,
+    user: Option<User>,
+    permit: Permit,
+    write: F,
+) -> Result<JoinHandle<Result<i64>>>
+where
+    F: FnOnce(Receiver<Result<Output>>) -> Result<()> + Send + 'static,
+{
+    // batch images fail on their own
+    let first = if params.batch {
+        None
+    } else {
+        Some(prepare(&params, &params.images[0]).await?)
+    };
+
+    let (entries_tx, entries_rx) = mpsc::channel(ENTRY_BUFFER);
+
+    let writer = tokio::task::spawn_blocking(move || write(entries_rx));
+
+    Ok(tokio::spawn(async move {
+        let _permit = permit;
+        let r = produce(&params, first, &entries_tx).await;
+
+        let r = match r {
+            Ok(count) => {
+                drop(entries_tx);
+                writer.await?.map(|_| count)
+            }
+            Err(e) => {
+                // the writer hands the error back after aborting its output
+                let _ = entries_tx.send(Err(e)).await;
+                drop(entries_tx);
+                writer
+                    .await?
+                    .and_then(|_| Err(Error::msg("resize task stopped")))
+            }
+        };
 
-    let options = SimpleFileOptions::default().compression_method(zip::CompressionMethod::Stored);
+        let use_ai_count = match r {
+            Ok(count) => count,
+            Err(e) => {
+                error!("{:?}", e);
+                return Err(e);
+            }
+        };
 
-    let mut img_url: Option<String> = Option::None;
+        if let Some(user) = user {
+            if use_ai_count > 0 {
+                if let Err(e) = update_credits(&user.email, -use_ai_count).await {
+                    error!("update credits error: {:?}", e);
+                }
+            }
+        }
 
-    let mut use_ai_count = 0;
+        Ok(use_ai_count)
+    }))
+}
 
-    for ele in &params.sizes {
-        if ele.use_ai {
-            if img_url.is_none() {
-                let filename = format!(
-                    "{}.{}",
-                    uuid::Uuid::new_v4(),
-                    params.target_img_type.extensions_str()[0]
-                );
+/// a decoded source ready for resizing
+struct Prepared {
+    image: Arc<DynamicImage>,
+    frame: Arc<Frame>,
+    /// format every output of this image is encoded in
+    format: ImageFormat,
+    /// uploaded copy for replicate, only when an ai size is asked for
+    img_url: Option<String>,
+}
+
+async fn prepare(params: &ImageResizeParams, source: &SourceImage) -> Result<Prepared> {
+    let image = source.image().await?;
+
+    let mut img_url = None;
+    if params.sizes.iter().any(|ele| ele.use_ai) {
+        let filename = format!(
+            "{}.{}",
+            uuid::Uuid::new_v4(),
+            source.target_img_type.extensions_str()[0]
+        );
+
+        let pic = image.clone();
+        let target_img_type = source.target_img_type;
+        let buffer = pool::run(move || format::transform(&pic, target_img_type)).await??;
+
+        img_url = Some(
+            upload_temp(buffer, &filename)
+                .await
+                .context(AppError::new(ErrorCode::Upstream, "upload for ai failed"))?,
+        );
+    }
+
+    let pic = image.clone();
+    let frame = Arc::new(pool::run(move || Frame::from_image(&pic)).await??);
+
+    let format = params.output_format(source, image.color().has_alpha());
+
+    Ok(Prepared {
+        image,
+        frame,
+        format,
+        img_url,
+    })
+}
+
+/// send the outputs of every image to the archive writer, one image after another,
+/// returns the number of ai outputs to charge
+async fn produce(
+    params: &ImageResizeParams,
+    mut first: Option<Prepared>,
+    entries: &Sender<Result<Output>>,
+) -> Result<i64> {
+    let mut names = UniqueNames::default();
+    names.claim(MANIFEST_NAME.to_string());
+
+    let mut use_ai_count = 0;
 
-                let buffer = transform(&params.image, params.target_img_type)?;
+    for source in &params.images {
+        let prepared = match first.take() {
+            Some(prepared) => Ok(prepared),
+            None => prepare(params, source).await,
+        };
 
-                let r = upload_temp(buffer, &filename).await?;
+        let r = match prepared {
+            Ok(prepared) => {
+                produce_image(
+                    params,
+                    source,
+                    prepared,
+                    &mut names,
+                    &mut use_ai_count,
+                    entries,
+                )
+                .await
+            }
+            Err(e) => Err(e),
+        };
 
-                img_url = Some(r);
+        if let Err(e) = r {
+            if !params.batch || entries.is_closed() {
+                return Err(e);
             }
 
-            use_ai_count += 1;
+            warn!("batch image {} failed: {:?}", source.path, e);
+            let failure = Failure {
+                name: source.path.clone(),
+                error: e.to_string(),
+            };
+            if entries.send(Ok(Output::Failure(failure))).await.is_err() {
+                return Err(Error::msg("archive writer stopped"));
+            }
         }
     }
 
-    if use_ai_count > 0 {
-        if let Some(ref user) = user {
-            if user.user.credit <= 0 {
-                return Ok(gen_known_err_response("credits is not enough"));
-            }
-        }
+    Ok(use_ai_count)
+}
+
+/// batch outputs keep the input folder, and get a folder per image unless the
+/// template already has `{name}`
+fn output_path(params: &ImageResizeParams, source: &SourceImage, name: String) -> String {
+    if !params.batch {
+        return name;
     }
-    for ele in &params.sizes {
-        let buf = if ele.use_ai {
-            // use ai
-            ai::resize(img_url.as_ref().unwrap(), ele.scale).await?
+
+    if params.filename_template.has_name() {
+        filename::sanitize(&format!("{}/{}", source.dir(), name))
+    } else {
+        filename::sanitize(&format!("{}/{}/{}", source.dir(), source.stem(), name))
+    }
+}
+
+/// algorithm sizes of one image, every size is resized and encoded in pool jobs of its own
+/// so nothing ever waits on a pool thread
+struct Resizes {
+    source: Arc<Frame>,
+    targets: Vec<Target>,
+    /// see `algorithm::cascade_plan`, all `None` without cascade
+    parents: Vec<Option<usize>>,
+    /// frames other targets cascade from, made by whichever target needs one first
+    frames: Vec<OnceCell<Arc<Frame>>>,
+    format: ImageFormat,
+    quality: Option<u8>,
+}
+
+impl Resizes {
+    fn new(
+        source: Arc<Frame>,
+        targets: Vec<Target>,
+        cascade: bool,
+        format: ImageFormat,
+        quality: Option<u8>,
+    ) -> Resizes {
+        let parents = if cascade {
+            algorithm::cascade_plan((source.width(), source.height()), &targets)
         } else {
-            // use algorithm
-            algorithm::resize(&params.image, params.target_img_type, ele.scale)?
+            vec![None; targets.len()]
         };
 
-        let ext = params.target_img_type.extensions_str()[0];
-        zip.start_file(
-            generate_file_name(params.width, params.height, ele, ext),
-            options,
-        )?;
-        zip.write_all(buf.borrow())?;
+        Resizes {
+            source,
+            frames: targets.iter().map(|_| OnceCell::new()).collect(),
+            targets,
+            parents,
+            format,
+            quality,
+        }
     }
 
-    zip.finish()?;
+    /// the frame of target `i`, kept while the image is produced when others cascade from it
+    fn frame(self: Arc<Self>, i: usize) -> BoxFuture<'static, Result<Arc<Frame>>> {
+        Box::pin(async move {
+            if !self.parents.contains(&Some(i)) {
+                return self.resize(i).await;
+            }
 
-    let mut file = std::fs::File::open(path)?;
-    let mut buffer = Vec::new();
-    file.read_to_end(&mut buffer)?;
+            self.frames[i]
+                .get_or_try_init(|| self.clone().resize(i))
+                .await
+                .cloned()
+        })
+    }
+
+    async fn resize(self: Arc<Self>, i: usize) -> Result<Arc<Frame>> {
+        let target = self.targets[i];
+        let (from, crop) = match self.parents[i] {
+            // parents are whole images already
+            Some(p) => (self.clone().frame(p).await?, None),
+            None => (self.source.clone(), target.crop),
+        };
 
-    if user.is_some() && use_ai_count > 0 {
-        let mut user = user.unwrap();
-        update_credits(&mut user.user, -use_ai_count).await?;
+        let frame =
+            pool::run(move || from.resize_cropped(target.width, target.height, crop)).await??;
+        Ok(Arc::new(frame))
     }
 
-    Ok(Response::builder()
-        .content_type("application/octet-stream")
-        .body(Body::from_vec(buffer)))
+    async fn encode(self: Arc<Self>, i: usize) -> Result<Bytes> {
+        let frame = self.clone().frame(i).await?;
+        let (format, quality) = (self.format, self.quality);
+
+        Ok(pool::run(move || frame.encode_with_quality(format, quality)).await??)
+    }
 }
 
-fn generate_file_name(width: u32, height: u32, size: &Size, ext: &str) -> String {
-    format!(
-        "@{}x{}.{}",
-        (width as f32 * size.scale) as u32,
-        (height as f32 * size.scale) as u32,
-        ext
-    )
+/// outputs of one image, algorithm sizes are resized in parallel on the resize pool while
+/// ai sizes wait on replicate
+///
+/// outputs are sent in `sizes` order, algorithm sizes that finish early wait for the ones
+/// before them, at most one per pool thread is started ahead of the writer so a slow
+/// client never holds more than that per image
+async fn produce_image(
+    params: &ImageResizeParams,
+    source: &SourceImage,
+    prepared: Prepared,
+    names: &mut UniqueNames,
+    use_ai_count: &mut i64,
+    entries: &Sender<Result<Output>>,
+) -> Result<()> {
+    let Prepared {
+        image,
+        frame,
+        format: target_img_type,
+        img_url,
+    } = prepared;
+
+    let cascade = params.cascade;
+    let quality = params.quality;
+    let source_size = (frame.width(), frame.height());
+    let targets: Vec<Target> = params
+        .sizes
+        .iter()
+        .filter(|ele| !ele.use_ai)
+        .map(|ele| ele.target(source_size))
+        .collect();
+
+    // batch images skip the check against the image in `validate`
+    if let Some(target) = targets
+        .iter()
+        .find(|t| t.width > MAX_OUTPUT_SIDE || t.height > MAX_OUTPUT_SIDE)
+    {
+        return Err(AppError::invalid_params(format!(
+            "output {}x{} is larger than {}",
+            target.width, target.height, MAX_OUTPUT_SIDE
+        ))
+        .into());
+    }
+
+    let resizes = Arc::new(Resizes::new(
+        frame,
+        targets.clone(),
+        cascade,
+        target_img_type,
+        quality,
+    ));
+    // a permit is held from the start of a size until the writer takes it
+    let permits = Arc::new(Semaphore::new(pool::threads().max(ENTRY_BUFFER)));
+    let (done_tx, mut done_rx) = mpsc::unbounded_channel();
+
+    // dropped with this function, which aborts every size still running
+    // sizes start in order, so the next one to send is always running and the sizes
+    // finished after it can't take every permit
+    let mut scheduler = JoinSet::new();
+    scheduler.spawn(async move {
+        let mut jobs = JoinSet::new();
+        for i in 0..resizes.targets.len() {
+            let Ok(permit) = permits.clone().acquire_owned().await else {
+                break;
+            };
+            let resizes = resizes.clone();
+            let done_tx = done_tx.clone();
+            jobs.spawn(async move {
+                let r = resizes.encode(i).await;
+                let _ = done_tx.send((i, r, permit));
+            });
+        }
+        while jobs.join_next().await.is_some() {}
+    });
+
+    let ext = target_img_type.extensions_str()[0];
+
+    // finished algorithm sizes waiting for their turn, by target index
+    let mut finished = HashMap::new();
+    let mut next_target = 0;
+
+    for (index, ele) in params.sizes.iter().enumerate() {
+        let (index, ele, buf, (width, height), _permit) = if ele.use_ai {
+            // use ai
+            let buf = ai::resize(img_url.as_ref().unwrap(), ele.scale).await?;
+            // replicate picks its own format
+            let buf =
+                if image::guess_format(&buf).ok() == Some(target_img_type) && quality.is_none() {
+                    buf
+                } else {
+                    pool::run(move || {
+                        let pic = ImageReader::new(Cursor::new(&buf))
+                            .with_guessed_format()?
+                            .decode()?;
+                        Frame::from_image(&pic)?.encode_with_quality(target_img_type, quality)
+                    })
+                    .await??
+                };
+            let dimensions = ImageReader::new(Cursor::new(&buf))
+                .with_guessed_format()?
+                .into_dimensions()
+                .unwrap_or_else(|_| {
+                    algorithm::target_size(image.width(), image.height(), ele.scale)
+                });
+            (index, ele, buf, dimensions, None)
+        } else {
+            // use algorithm
+            let i = next_target;
+            next_target += 1;
+
+            let (buf, permit) = loop {
+                if let Some(done) = finished.remove(&i) {
+                    break done;
+                }
+                match done_rx.recv().await {
+                    Some((j, Ok(buf), permit)) => {
+                        finished.insert(j, (buf, permit));
+                    }
+                    Some((_, Err(e), _)) => return Err(e),
+                    None => return Err(Error::msg("resize task stopped")),
+                }
+            };
+            (index, ele, buf, targets[i].size(), Some(permit))
+        };
+
+        let name = params.filename_template.render(&FileVars {
+            name: source.stem(),
+            width,
+            height,
+            scale: ele.scale_of(source_size),
+            format: ext,
+            index: index + 1,
+        });
+        let entry = Entry {
+            name: names.claim(output_path(params, source, name)),
+            content_type: target_img_type.to_mime_type().to_string(),
+            width: Some(width),
+            height: Some(height),
+            method: Some(if ele.use_ai {
+                ResizeMethod::Ai
+            } else {
+                ResizeMethod::Algorithm
+            }),
+            size: Some(index),
+            // one credit per ai size, charged once the whole response is produced
+            credits: if ele.use_ai { 1 } else { 0 },
+            data: buf,
+        };
+        if ele.use_ai {
+            *use_ai_count += 1;
+        }
+        if entries.send(Ok(Output::Entry(entry))).await.is_err() {
+            return Err(Error::msg("archive writer stopped"));
+        }
+    }
+
+    if let Some(count) = params.palette {
+        let p = pool::run(move || palette::extract(&image, count)).await?;
+        let entry = Entry {
+            name: names.claim(output_path(params, source, "palette.json".to_string())),
+            content_type: "application/json".to_string(),
+            width: None,
+            height: None,
+            method: None,
+            size: None,
+            credits: 0,
+            data: Bytes::from(serde_json::to_vec(&p)?),
+        };
+        if entries.send(Ok(Output::Entry(entry))).await.is_err() {
+            return Err(Error::msg("archive writer stopped"));
+        }
+    }
+
+    Ok(())
 }
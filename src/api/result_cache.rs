@@ -0,0 +1,439 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    env, fs,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex, OnceLock,
+    },
+    time::{Duration, SystemTime},
+};
+
+use anyhow::Result;
+use bytes::Bytes;
+use poem_openapi::{payload::Json, Object, OpenApi};
+use serde::{Deserialize, Serialize};
+use tracing::{error, warn};
+
+use super::{
+    openapi::{ApiTags, TokenAuth},
+    stream::{Entry, Output, ResizeMethod},
+};
+use crate::status_code::AppResult;
+
+static CONFIG: OnceLock<CacheConfig> = OnceLock::new();
+static MEMORY: OnceLock<Mutex<Memory>> = OnceLock::new();
+
+static MEMORY_HITS: AtomicU64 = AtomicU64::new(0);
+static DISK_HITS: AtomicU64 = AtomicU64::new(0);
+static MISSES: AtomicU64 = AtomicU64::new(0);
+static STORES: AtomicU64 = AtomicU64::new(0);
+static EVICTIONS: AtomicU64 = AtomicU64::new(0);
+
+const META_FILE: &str = "meta.json";
+const DATA_FILE: &str = "data";
+
+/// expired disk entries are removed this often
+const SWEEP_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+/// whether outputs served from the cache still cost their ai credits
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AiCreditPolicy {
+    Charge,
+    Free,
+}
+
+struct CacheConfig {
+    /// byte budget of the in-memory tier, 0 turns it off
+    memory_bytes: usize,
+    /// bigger results are streamed without being kept
+    max_entry_bytes: usize,
+    /// on-disk tier, off when unset
+    dir: Option<PathBuf>,
+    ttl: Duration,
+    ai_credits: AiCreditPolicy,
+}
+
+impl CacheConfig {
+    /// RESULT_CACHE_MEMORY_BYTES (256MB), RESULT_CACHE_MAX_ENTRY_BYTES (64MB), RESULT_CACHE_DIR,
+    /// RESULT_CACHE_TTL_SECS (7 days) and RESULT_CACHE_AI_CREDITS (`charge` or `free`)
+    fn from_env() -> CacheConfig {
+        let number = |key: &str, default: u64| {
+            env::var(key)
+                .ok()
+                .and_then(|v| v.parse::<u64>().ok())
+                .unwrap_or(default)
+        };
+
+        let ai_credits = match env::var("RESULT_CACHE_AI_CREDITS").as_deref() {
+            Ok("free") => AiCreditPolicy::Free,
+            Ok("charge") | Err(_) => AiCreditPolicy::Charge,
+            Ok(other) => {
+                warn!("RESULT_CACHE_AI_CREDITS {} is unknown, charging", other);
+                AiCreditPolicy::Charge
+            }
+        };
+
+        CacheConfig {
+            memory_bytes: number("RESULT_CACHE_MEMORY_BYTES", 256 * 1024 * 1024) as usize,
+            max_entry_bytes: number("RESULT_CACHE_MAX_ENTRY_BYTES", 64 * 1024 * 1024) as usize,
+            dir: env::var("RESULT_CACHE_DIR")
+                .ok()
+                .filter(|d| !d.is_empty())
+                .map(PathBuf::from),
+            ttl: Duration::from_secs(number("RESULT_CACHE_TTL_SECS", 7 * 24 * 60 * 60)),
+            ai_credits,
+        }
+    }
+
+    fn is_enabled(&self) -> bool {
+        self.memory_bytes > 0 || self.dir.is_some()
+    }
+}
+
+fn get_config() -> &'static CacheConfig {
+    CONFIG.get_or_init(CacheConfig::from_env)
+}
+
+pub fn ai_credit_policy() -> AiCreditPolicy {
+    get_config().ai_credits
+}
+
+/// least recently used results are evicted once `bytes` passes the budget
+#[derive(Default)]
+struct Memory {
+    results: HashMap<String, Cached>,
+    /// last use tick to key
+    order: BTreeMap<u64, String>,
+    tick: u64,
+    bytes: usize,
+}
+
+struct Cached {
+    entries: Vec<Entry>,
+    bytes: usize,
+    tick: u64,
+    ctime: SystemTime,
+}
+
+impl Memory {
+    fn get(&mut self, key: &str, ttl: Duration) -> Option<Vec<Entry>> {
+        let expired = is_expired(self.results.get(key)?.ctime, ttl);
+        if expired {
+            self.remove(key);
+            return None;
+        }
+
+        self.tick += 1;
+        let tick = self.tick;
+        let cached = self.results.get_mut(key)?;
+        self.order.remove(&cached.tick);
+        self.order.insert(tick, key.to_string());
+        cached.tick = tick;
+
+        Some(cached.entries.clone())
+    }
+
+    fn insert(&mut self, key: String, entries: Vec<Entry>, bytes: usize, budget: usize) {
+        self.remove(&key);
+
+        while self.bytes + bytes > budget {
+            let Some((_, oldest)) = self.order.pop_first() else {
+                break;
+            };
+            if let Some(cached) = self.results.remove(&oldest) {
+                self.bytes -= cached.bytes;
+                EVICTIONS.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+
+        self.tick += 1;
+        self.order.insert(self.tick, key.clone());
+        self.bytes += bytes;
+        self.results.insert(
+            key,
+            Cached {
+                entries,
+                bytes,
+                tick: self.tick,
+                ctime: SystemTime::now(),
+            },
+        );
+    }
+
+    fn remove(&mut self, key: &str) {
+        if let Some(cached) = self.results.remove(key) {
+            self.order.remove(&cached.tick);
+            self.bytes -= cached.bytes;
+        }
+    }
+}
+
+fn get_memory() -> &'static Mutex<Memory> {
+    MEMORY.get_or_init(|| Mutex::new(Memory::default()))
+}
+
+fn is_expired(ctime: SystemTime, ttl: Duration) -> bool {
+    ctime.elapsed().is_ok_and(|age| age > ttl)
+}
+
+/// an entry in the meta file, its data is the next `len` bytes of the data file
+#[derive(Serialize, Deserialize)]
+struct DiskEntry {
+    name: String,
+    content_type: String,
+    width: Option<u32>,
+    height: Option<u32>,
+    method: Option<ResizeMethod>,
+    size: Option<usize>,
+    credits: i64,
+    len: usize,
+}
+
+/// outputs of an earlier identical request, memory first then disk
+pub async fn get(key: &str) -> Option<Vec<Entry>> {
+    let config = get_config();
+    if !config.is_enabled() {
+        return None;
+    }
+
+    if let Some(entries) = get_memory().lock().unwrap().get(key, config.ttl) {
+        MEMORY_HITS.fetch_add(1, Ordering::Relaxed);
+        return Some(entries);
+    }
+
+    if let Some(dir) = &config.dir {
+        match read_disk(&dir.join(key), config.ttl).await {
+            Ok(Some(entries)) => {
+                DISK_HITS.fetch_add(1, Ordering::Relaxed);
+                insert_memory(key.to_string(), entries.clone());
+                return Some(entries);
+            }
+            Ok(None) => {}
+            Err(e) => warn!("read cached result {} error: {:?}", key, e),
+        }
+    }
+
+    MISSES.fetch_add(1, Ordering::Relaxed);
+    None
+}
+
+async fn read_disk(dir: &Path, ttl: Duration) -> Result<Option<Vec<Entry>>> {
+    let meta = match tokio::fs::metadata(dir.join(META_FILE)).await {
+        Ok(meta) => meta,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    if is_expired(meta.modified()?, ttl) {
+        return Ok(None);
+    }
+
+    let disk_entries: Vec<DiskEntry> =
+        serde_json::from_slice(&tokio::fs::read(dir.join(META_FILE)).await?)?;
+    let data = Bytes::from(tokio::fs::read(dir.join(DATA_FILE)).await?);
+
+    let mut entries = vec![];
+    let mut offset = 0;
+    for e in disk_entries {
+        if offset + e.len > data.len() {
+            return Err(anyhow::Error::msg("cached data is truncated"));
+        }
+        entries.push(Entry {
+            name: e.name,
+            content_type: e.content_type,
+            width: e.width,
+            height: e.height,
+            method: e.method,
+            size: e.size,
+            credits: e.credits,
+            data: data.slice(offset..offset + e.len),
+        });
+        offset += e.len;
+    }
+
+    Ok(Some(entries))
+}
+
+fn insert_memory(key: String, entries: Vec<Entry>) {
+    let budget = get_config().memory_bytes;
+    let bytes = entries.iter().map(|e| e.data.len()).sum();
+    if bytes > budget {
+        return;
+    }
+
+    get_memory()
+        .lock()
+        .unwrap()
+        .insert(key, entries, bytes, budget);
+}
+
+/// keep the outputs of a finished request, blocks on the disk tier
+fn insert(key: String, entries: Vec<Entry>) {
+    if let Some(dir) = &get_config().dir {
+        if let Err(e) = write_disk(dir, &key, &entries) {
+            error!("write cached result {} error: {:?}", key, e);
+        }
+    }
+
+    insert_memory(key, entries);
+    STORES.fetch_add(1, Ordering::Relaxed);
+}
+
+/// written aside and renamed into place, readers never see half a result
+fn write_disk(dir: &Path, key: &str, entries: &[Entry]) -> Result<()> {
+    let tmp = dir.join(format!("{}.tmp-{}", key, uuid::Uuid::new_v4().simple()));
+    fs::create_dir_all(&tmp)?;
+
+    let disk_entries: Vec<DiskEntry> = entries
+        .iter()
+        .map(|e| DiskEntry {
+            name: e.name.clone(),
+            content_type: e.content_type.clone(),
+            width: e.width,
+            height: e.height,
+            method: e.method,
+            size: e.size,
+            credits: e.credits,
+            len: e.data.len(),
+        })
+        .collect();
+    let data: Vec<u8> = entries
+        .iter()
+        .flat_map(|e| e.data.iter().copied())
+        .collect();
+
+    fs::write(tmp.join(DATA_FILE), data)?;
+    fs::write(tmp.join(META_FILE), serde_json::to_vec(&disk_entries)?)?;
+
+    let target = dir.join(key);
+    let _ = fs::remove_dir_all(&target);
+    if let Err(e) = fs::rename(&tmp, &target) {
+        let _ = fs::remove_dir_all(&tmp);
+        return Err(e.into());
+    }
+
+    Ok(())
+}
+
+/// copies the outputs of a miss as they stream by, gives up on a failed input or
+/// once they pass the size limit
+pub struct Capture {
+    key: String,
+    entries: Option<Vec<Entry>>,
+    bytes: usize,
+}
+
+impl Capture {
+    pub fn new(key: String) -> Capture {
+        let enabled = get_config().is_enabled();
+        Capture {
+            key,
+            entries: enabled.then(Vec::new),
+            bytes: 0,
+        }
+    }
+
+    pub fn record(&mut self, output: &Result<Output>) {
+        let Some(entries) = &mut self.entries else {
+            return;
+        };
+
+        match output {
+            Ok(Output::Entry(entry)) => {
+                self.bytes += entry.data.len();
+                if self.bytes > get_config().max_entry_bytes {
+                    self.entries = None;
+                } else {
+                    entries.push(entry.clone());
+                }
+            }
+            // a failed input may succeed next time
+            Ok(Output::Failure(_)) | Err(_) => self.entries = None,
+        }
+    }
+
+    /// only called once every output was written
+    pub fn store(self) {
+        if let Some(entries) = self.entries {
+            insert(self.key, entries);
+        }
+    }
+}
+
+/// remove expired results from the disk tier every SWEEP_INTERVAL
+pub fn start_sweeper() {
+    let Some(dir) = get_config().dir.clone() else {
+        return;
+    };
+
+    tokio::spawn(async move {
+        loop {
+            if let Err(e) = sweep(&dir, get_config().ttl).await {
+                error!("sweep result cache error: {:?}", e);
+            }
+            tokio::time::sleep(SWEEP_INTERVAL).await;
+        }
+    });
+}
+
+async fn sweep(dir: &Path, ttl: Duration) -> Result<()> {
+    let mut entries = match tokio::fs::read_dir(dir).await {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e.into()),
+    };
+
+    while let Some(entry) = entries.next_entry().await? {
+        let expired = match entry.metadata().await.and_then(|m| m.modified()) {
+            Ok(mtime) => is_expired(mtime, ttl),
+            Err(_) => true,
+        };
+        if expired {
+            let _ = tokio::fs::remove_dir_all(entry.path()).await;
+        }
+    }
+
+    Ok(())
+}
+
+#[derive(Object)]
+struct CacheStats {
+    memory_hits: u64,
+    disk_hits: u64,
+    misses: u64,
+    stores: u64,
+    evictions: u64,
+    memory_results: usize,
+    memory_bytes: usize,
+    memory_budget: usize,
+    disk: bool,
+}
+
+pub struct CacheApi;
+
+#[OpenApi(tag = "ApiTags::Cache")]
+impl CacheApi {
+    /// hits, misses and size of the result cache, admins only
+    #[oai(path = "/cache/stats", method = "get")]
+    async fn stats(&self, auth: TokenAuth) -> AppResult<Json<CacheStats>> {
+        auth.require_admin()?;
+
+        let config = get_config();
+        let (memory_results, memory_bytes) = {
+            let memory = get_memory().lock().unwrap();
+            (memory.results.len(), memory.bytes)
+        };
+
+        Ok(Json(CacheStats {
+            memory_hits: MEMORY_HITS.load(Ordering::Relaxed),
+            disk_hits: DISK_HITS.load(Ordering::Relaxed),
+            misses: MISSES.load(Ordering::Relaxed),
+            stores: STORES.load(Ordering::Relaxed),
+            evictions: EVICTIONS.load(Ordering::Relaxed),
+            memory_results,
+            memory_bytes,
+            memory_budget: config.memory_bytes,
+            disk: config.dir.is_some(),
+        }))
+    }
+}
@@ -0,0 +1,602 @@
+use std::{
+    io::{self, Write},
+    time::SystemTime,
+};
+
+use anyhow::{Error, Result};
+use base64::{prelude::BASE64_STANDARD, Engine};
+use bytes::Bytes;
+use futures_util::stream;
+use image::ImageFormat;
+use poem::{Body, IntoResponse, Response};
+use poem_openapi::{
+    registry::{MetaMediaType, MetaSchema, MetaSchemaRef},
+    ResponseContent,
+};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::sync::mpsc::{self, Sender};
+use zip::{
+    write::{SimpleFileOptions, StreamWriter},
+    CompressionMethod, ZipWriter,
+};
+
+/// bytes buffered before a chunk is handed to the response body
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// chunks in flight between the archive writer and the client
+const BODY_BUFFER: usize = 8;
+
+/// entries bigger than this get zip64 headers
+const ZIP64_THRESHOLD: u64 = u32::MAX as u64;
+
+/// name reserved for the manifest in zip and tar archives
+pub const MANIFEST_NAME: &str = "manifest.json";
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum ResizeMethod {
+    Ai,
+    Algorithm,
+}
+
+/// one file of the response archive
+#[derive(Clone)]
+pub struct Entry {
+    pub name: String,
+    pub content_type: String,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    /// `None` for files that are not resized images, e.g. palette.json
+    pub method: Option<ResizeMethod>,
+    /// position in `sizes`, `None` for files that are not resized images
+    pub size: Option<usize>,
+    pub credits: i64,
+    pub data: Bytes,
+}
+
+/// an input that produced no output, batches report these instead of failing
+#[derive(Serialize, Clone)]
+pub struct Failure {
+    pub name: String,
+    pub error: String,
+}
+
+/// what the producer hands the archive writer
+pub enum Output {
+    Entry(Entry),
+    Failure(Failure),
+}
+
+#[derive(Serialize)]
+struct ManifestEntry {
+    name: String,
+    format: String,
+    width: Option<u32>,
+    height: Option<u32>,
+    size: usize,
+    sha256: String,
+    method: Option<ResizeMethod>,
+    credits: i64,
+}
+
+impl ManifestEntry {
+    fn from(entry: &Entry) -> Self {
+        Self {
+            name: entry.name.clone(),
+            format: entry.content_type.clone(),
+            width: entry.width,
+            height: entry.height,
+            size: entry.data.len(),
+            sha256: format!("{:x}", Sha256::digest(&entry.data)),
+            method: entry.method,
+            credits: entry.credits,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct Manifest {
+    entries: Vec<ManifestEntry>,
+    failures: Vec<Failure>,
+    credits: i64,
+}
+
+/// zip entry compression, `Stored` unless the client asks otherwise
+#[derive(Clone, Copy, Debug)]
+pub struct ZipCompression {
+    method: CompressionMethod,
+    level: Option<i64>,
+}
+
+impl ZipCompression {
+    /// deflate levels 0-9, zstd levels -7-22
+    pub fn parse(method: &str, level: Option<i64>) -> Result<ZipCompression> {
+        let (method, range, default_level) = match method {
+            "stored" => (CompressionMethod::Stored, None, None),
+            // an explicit level keeps the zip crate off zopfli, which is far too slow here
+            "deflate" => (CompressionMethod::Deflated, Some(0..=9), Some(6)),
+            "zstd" => (CompressionMethod::Zstd, Some(-7..=22), Some(3)),
+            _ => return Err(Error::msg(format!("compression {} is not support", method))),
+        };
+
+        let level = match (level, range) {
+            (Some(level), Some(range)) if range.contains(&level) => Some(level),
+            (Some(level), _) => {
+                return Err(Error::msg(format!(
+                    "compression level {} is out of range",
+                    level
+                )))
+            }
+            (None, _) => default_level,
+        };
+
+        Ok(ZipCompression { method, level })
+    }
+}
+
+impl Default for ZipCompression {
+    fn default() -> Self {
+        ZipCompression {
+            method: CompressionMethod::Stored,
+            level: None,
+        }
+    }
+}
+
+/// blocking `io::Write` side of a streamed response body, must not be used on the runtime
+pub struct BodyWriter {
+    tx: Sender<io::Result<Bytes>>,
+    buf: Vec<u8>,
+}
+
+impl BodyWriter {
+    fn send_chunk(&mut self) -> io::Result<()> {
+        if self.buf.is_empty() {
+            return Ok(());
+        }
+
+        let chunk = Bytes::from(std::mem::replace(
+            &mut self.buf,
+            Vec::with_capacity(CHUNK_SIZE),
+        ));
+
+        self.tx
+            .blocking_send(Ok(chunk))
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "client disconnected"))
+    }
+
+    pub fn abort_handle(&self) -> BodyAbort {
+        BodyAbort(self.tx.clone())
+    }
+}
+
+pub struct BodyAbort(Sender<io::Result<Bytes>>);
+
+impl BodyAbort {
+    /// the client sees a broken transfer instead of a truncated archive
+    pub fn abort(self, msg: String) {
+        let _ = self.0.blocking_send(Err(io::Error::other(msg)));
+    }
+}
+
+impl Write for BodyWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buf.extend_from_slice(buf);
+        if self.buf.len() >= CHUNK_SIZE {
+            self.send_chunk()?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.send_chunk()
+    }
+}
+
+pub fn body_channel() -> (BodyWriter, Body) {
+    let (tx, rx) = mpsc::channel(BODY_BUFFER);
+
+    let body = Body::from_bytes_stream(stream::unfold(rx, |mut rx| async move {
+        rx.recv().await.map(|chunk| (chunk, rx))
+    }));
+
+    (
+        BodyWriter {
+            tx,
+            buf: Vec::with_capacity(CHUNK_SIZE),
+        },
+        body,
+    )
+}
+
+/// a streamed archive or image, its content type depends on the response format
+pub struct Download {
+    pub content_type: String,
+    pub body: Body,
+}
+
+impl IntoResponse for Download {
+    fn into_response(self) -> Response {
+        Response::builder()
+            .content_type(self.content_type)
+            .body(self.body)
+    }
+}
+
+impl ResponseContent for Download {
+    fn media_types() -> Vec<MetaMediaType> {
+        [
+            "application/octet-stream",
+            "application/x-tar",
+            "multipart/mixed",
+            "application/json",
+            "image/*",
+        ]
+        .into_iter()
+        .map(|content_type| MetaMediaType {
+            content_type,
+            schema: MetaSchemaRef::Inline(Box::new(MetaSchema::new_with_format(
+                "string", "binary",
+            ))),
+        })
+        .collect()
+    }
+}
+
+/// shape of the response body, picked by the `response` param or the Accept header
+#[derive(Clone, Debug, PartialEq)]
+pub enum ResponseFormat {
+    Zip,
+    Tar,
+    Multipart {
+        boundary: String,
+    },
+    /// every entry with its metadata and base64 data
+    Json,
+    /// the raw bytes of a single output
+    Image,
+}
+
+impl ResponseFormat {
+    pub fn from_name(name: &str) -> Option<ResponseFormat> {
+        match name {
+            "zip" => Some(ResponseFormat::Zip),
+            "tar" => Some(ResponseFormat::Tar),
+            "multipart" => Some(ResponseFormat::Multipart {
+                boundary: uuid::Uuid::new_v4().simple().to_string(),
+            }),
+            "json" => Some(ResponseFormat::Json),
+            "image" => Some(ResponseFormat::Image),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            ResponseFormat::Zip => "zip",
+            ResponseFormat::Tar => "tar",
+            ResponseFormat::Multipart { .. } => "multipart",
+            ResponseFormat::Json => "json",
+            ResponseFormat::Image => "image",
+        }
+    }
+
+    /// highest weighted media range we can produce, ties go to header order
+    pub fn from_accept(accept: &str) -> Option<ResponseFormat> {
+        let mut best: Option<(&str, f32)> = None;
+
+        for range in accept.split(',') {
+            let mut parts = range.split(';').map(str::trim);
+            let media = parts.next().unwrap_or("");
+            let q = parts
+                .filter_map(|p| p.strip_prefix("q="))
+                .find_map(|q| q.parse::<f32>().ok())
+                .unwrap_or(1.0);
+
+            let name = match media {
+                "application/zip" | "application/x-zip-compressed" => "zip",
+                "application/x-tar" | "application/tar" => "tar",
+                "multipart/mixed" => "multipart",
+                "application/json" => "json",
+                _ if media.starts_with("image/") => "image",
+                _ => continue,
+            };
+
+            // q=0 means "not acceptable"
+            if q > 0.0 && best.is_none_or(|(_, best_q)| q > best_q) {
+                best = Some((name, q));
+            }
+        }
+
+        best.and_then(|(name, _)| ResponseFormat::from_name(name))
+    }
+
+    pub fn content_type(&self, image_type: ImageFormat) -> String {
+        match self {
+            ResponseFormat::Zip => "application/octet-stream".to_string(),
+            ResponseFormat::Tar => "application/x-tar".to_string(),
+            ResponseFormat::Multipart { boundary } => {
+                format!("multipart/mixed; boundary={}", boundary)
+            }
+            ResponseFormat::Json => "application/json".to_string(),
+            ResponseFormat::Image => image_type.to_mime_type().to_string(),
+        }
+    }
+}
+
+trait ArchiveWriter {
+    fn add(&mut self, entry: Entry) -> Result<()>;
+    /// formats without a manifest report failed inputs themselves
+    fn fail(&mut self, _failure: &Failure) {}
+    fn finish(self: Box<Self>) -> Result<()>;
+}
+
+struct ZipArchive<W: Write> {
+    zip: ZipWriter<StreamWriter<W>>,
+    compression: ZipCompression,
+}
+
+impl<W: Write> ArchiveWriter for ZipArchive<W> {
+    fn add(&mut self, entry: Entry) -> Result<()> {
+        let options = SimpleFileOptions::default()
+            .compression_method(self.compression.method)
+            .compression_level(self.compression.level)
+            .large_file(entry.data.len() as u64 >= ZIP64_THRESHOLD);
+
+        self.zip.start_file(entry.name, options)?;
+        self.zip.write_all(&entry.data)?;
+        Ok(())
+    }
+
+    fn finish(self: Box<Self>) -> Result<()> {
+        self.zip.finish()?.into_inner().flush()?;
+        Ok(())
+    }
+}
+
+struct TarArchive<W: Write>(tar::Builder<W>);
+
+impl<W: Write> ArchiveWriter for TarArchive<W> {
+    fn add(&mut self, entry: Entry) -> Result<()> {
+        let mtime = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)?
+            .as_secs();
+
+        let mut header = tar::Header::new_gnu();
+        header.set_size(entry.data.len() as u64);
+        header.set_mode(0o644);
+        header.set_mtime(mtime);
+
+        self.0
+            .append_data(&mut header, entry.name, entry.data.as_ref())?;
+        Ok(())
+    }
+
+    fn finish(self: Box<Self>) -> Result<()> {
+        self.0.into_inner()?.flush()?;
+        Ok(())
+    }
+}
+
+/// RFC 5987 value, names are sanitized already but non ascii needs this anyway
+fn encode_filename(name: &str) -> String {
+    name.bytes()
+        .map(|b| match b {
+            b'a'..=b'z'
+            | b'A'..=b'Z'
+            | b'0'..=b'9'
+            | b'!'
+            | b'#'
+            | b'$'
+            | b'&'
+            | b'+'
+            | b'-'
+            | b'.'
+            | b'^'
+            | b'_'
+            | b'`'
+            | b'|'
+            | b'~' => (b as char).to_string(),
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+struct MultipartArchive<W: Write> {
+    out: W,
+    boundary: String,
+}
+
+impl<W: Write> ArchiveWriter for MultipartArchive<W> {
+    fn add(&mut self, entry: Entry) -> Result<()> {
+        write!(
+            self.out,
+            "--{}\r\nContent-Type: {}\r\nContent-Disposition: attachment; filename=\"{}\"; filename*=UTF-8''{}\r\nContent-Length: {}\r\n\r\n",
+            self.boundary,
+            entry.content_type,
+            entry.name,
+            encode_filename(&entry.name),
+            entry.data.len()
+        )?;
+        self.out.write_all(&entry.data)?;
+        self.out.write_all(b"\r\n")?;
+        Ok(())
+    }
+
+    fn finish(mut self: Box<Self>) -> Result<()> {
+        write!(self.out, "--{}--\r\n", self.boundary)?;
+        self.out.flush()?;
+        Ok(())
+    }
+}
+
+#[derive(Serialize)]
+struct JsonEntry<'a> {
+    name: &'a str,
+    content_type: &'a str,
+    width: Option<u32>,
+    height: Option<u32>,
+    size: usize,
+    data: String,
+}
+
+struct JsonArchive<W: Write> {
+    out: W,
+    count: usize,
+    failures: Vec<Failure>,
+}
+
+impl<W: Write> ArchiveWriter for JsonArchive<W> {
+    fn add(&mut self, entry: Entry) -> Result<()> {
+        let sep = if self.count == 0 { "{\"files\":[" } else { "," };
+        self.out.write_all(sep.as_bytes())?;
+
+        serde_json::to_writer(
+            &mut self.out,
+            &JsonEntry {
+                name: &entry.name,
+                content_type: &entry.content_type,
+                width: entry.width,
+                height: entry.height,
+                size: entry.data.len(),
+                data: BASE64_STANDARD.encode(&entry.data),
+            },
+        )?;
+
+        self.count += 1;
+        Ok(())
+    }
+
+    fn fail(&mut self, failure: &Failure) {
+        self.failures.push(failure.clone());
+    }
+
+    fn finish(mut self: Box<Self>) -> Result<()> {
+        let end = if self.count == 0 {
+            "{\"files\":[]"
+        } else {
+            "]"
+        };
+        self.out.write_all(end.as_bytes())?;
+
+        if !self.failures.is_empty() {
+            self.out.write_all(b",\"failures\":")?;
+            serde_json::to_writer(&mut self.out, &self.failures)?;
+        }
+
+        self.out.write_all(b"}")?;
+        self.out.flush()?;
+        Ok(())
+    }
+}
+
+struct ImageBody<W: Write>(W);
+
+impl<W: Write> ArchiveWriter for ImageBody<W> {
+    fn add(&mut self, entry: Entry) -> Result<()> {
+        self.0.write_all(&entry.data)?;
+        Ok(())
+    }
+
+    fn finish(mut self: Box<Self>) -> Result<()> {
+        self.0.flush()?;
+        Ok(())
+    }
+}
+
+/// write entries into the response body as they arrive, an `Err` aborts the body
+pub fn write_archive<I>(
+    format: &ResponseFormat,
+    compression: ZipCompression,
+    outputs: I,
+    out: BodyWriter,
+) -> Result<()>
+where
+    I: Iterator<Item = Result<Output>>,
+{
+    let abort = out.abort_handle();
+
+    let r = write_outputs(format, compression, outputs, out);
+    if let Err(e) = &r {
+        abort.abort(e.to_string());
+    }
+
+    r
+}
+
+/// write outputs into `out` in `format`, stops at the first `Err`
+///
+/// zip and tar archives end with a manifest.json describing every entry and failed input
+pub fn write_outputs<W, I>(
+    format: &ResponseFormat,
+    compression: ZipCompression,
+    outputs: I,
+    out: W,
+) -> Result<()>
+where
+    W: Write + 'static,
+    I: Iterator<Item = Result<Output>>,
+{
+    let mut archive: Box<dyn ArchiveWriter> = match format {
+        // without seek the sizes and crc go into data descriptors after each entry
+        ResponseFormat::Zip => Box::new(ZipArchive {
+            zip: ZipWriter::new_stream(out),
+            compression,
+        }),
+        ResponseFormat::Tar => Box::new(TarArchive(tar::Builder::new(out))),
+        ResponseFormat::Multipart { boundary } => Box::new(MultipartArchive {
+            out,
+            boundary: boundary.clone(),
+        }),
+        ResponseFormat::Json => Box::new(JsonArchive {
+            out,
+            count: 0,
+            failures: vec![],
+        }),
+        ResponseFormat::Image => Box::new(ImageBody(out)),
+    };
+
+    let mut manifest = Manifest {
+        entries: vec![],
+        failures: vec![],
+        credits: 0,
+    };
+
+    for output in outputs {
+        match output {
+            Ok(Output::Entry(entry)) => {
+                manifest.credits += entry.credits;
+                manifest.entries.push(ManifestEntry::from(&entry));
+                archive.add(entry)?;
+            }
+            Ok(Output::Failure(failure)) => {
+                archive.fail(&failure);
+                manifest.failures.push(failure);
+            }
+            Err(e) => return Err(e),
+        }
+    }
+
+    // multipart has no manifest of its own, but failures must still reach the client
+    let with_manifest = match format {
+        ResponseFormat::Zip | ResponseFormat::Tar => true,
+        ResponseFormat::Multipart { .. } => !manifest.failures.is_empty(),
+        _ => false,
+    };
+
+    if with_manifest {
+        archive.add(Entry {
+            name: MANIFEST_NAME.to_string(),
+            content_type: "application/json".to_string(),
+            width: None,
+            height: None,
+            method: None,
+            size: None,
+            credits: 0,
+            data: Bytes::from(serde_json::to_vec_pretty(&manifest)?),
+        })?;
+    }
+
+    archive.finish()
+}
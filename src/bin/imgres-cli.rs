@@ -0,0 +1,310 @@
+//! resize files offline with the server's pipeline, no network, auth or credits
+//!
+//! `imgres-cli -s 0.5,1,2 -f webp -o "dist/{dir}" "assets/**/*.png"`
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    process::ExitCode,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
+};
+
+use anyhow::{Context, Error, Result};
+use clap::Parser;
+use image::{DynamicImage, ImageFormat, ImageReader};
+use image_resize_core::{
+    algorithm,
+    filename::{FileVars, FilenameTemplate, UniqueNames},
+    format::{self, ENCODE_FORMATS},
+    Crop, Fit, Frame,
+};
+use rayon::{
+    iter::{IndexedParallelIterator, IntoParallelRefIterator, ParallelIterator},
+    ThreadPoolBuilder,
+};
+
+#[derive(Parser)]
+#[command(name = "imgres-cli", version, about = "resize images offline")]
+struct Args {
+    /// files, directories (searched recursively) or globs like `assets/**/*.png`
+    #[arg(required = true)]
+    inputs: Vec<String>,
+
+    /// output directory, `{dir}` is the folder of an input below its directory or glob
+    #[arg(short, long, default_value = "out/{dir}")]
+    out: String,
+
+    /// output filename with `{name}`, `{w}`, `{h}`, `{scale}`, `{density}`, `{format}`
+    /// and `{index}`
+    #[arg(short, long, default_value = "{name}@{w}x{h}.{format}")]
+    name: String,
+
+    /// comma separated scales, e.g. `0.5,1,2`
+    #[arg(short, long, value_delimiter = ',')]
+    scale: Vec<f32>,
+
+    /// box to fit into, a missing side follows the aspect ratio
+    #[arg(short = 'W', long)]
+    width: Option<u32>,
+
+    #[arg(short = 'H', long)]
+    height: Option<u32>,
+
+    /// contain, cover or fill the width x height box
+    #[arg(long, default_value = "contain", value_parser = parse_fit)]
+    fit: Fit,
+
+    /// png, jpeg, webp or avif, the input's format when missing
+    #[arg(short, long, value_parser = parse_format)]
+    format: Option<ImageFormat>,
+
+    /// jpeg and avif quality 1-100
+    #[arg(short, long, value_parser = clap::value_parser!(u8).range(1..=100))]
+    quality: Option<u8>,
+
+    /// images resized at once, 0 is the number of cpus
+    #[arg(short, long, default_value_t = 0)]
+    jobs: usize,
+}
+
+/// one image to resize
+struct Input {
+    path: PathBuf,
+    /// folder below the directory or glob it was found by, empty for a plain file
+    dir: String,
+}
+
+/// one output of an image
+struct Target {
+    scale: f32,
+    size: (u32, u32),
+    crop: Option<Crop>,
+}
+
+fn parse_fit(name: &str) -> Result<Fit, String> {
+    Fit::from_name(name).ok_or_else(|| "expected contain, cover or fill".to_string())
+}
+
+fn parse_format(name: &str) -> Result<ImageFormat, String> {
+    format::from_name(name).ok_or_else(|| "expected png, jpeg, webp or avif".to_string())
+}
+
+fn main() -> ExitCode {
+    let args = Args::parse();
+
+    match run(&args) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("error: {:#}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn run(args: &Args) -> Result<()> {
+    if args.scale.is_empty() && args.width.is_none() && args.height.is_none() {
+        return Err(Error::msg("give --scale, --width or --height"));
+    }
+    if let Some(scale) = args.scale.iter().find(|s| !s.is_finite() || **s <= 0f32) {
+        return Err(Error::msg(format!(
+            "scale {} must be greater than 0",
+            scale
+        )));
+    }
+    if args.width == Some(0) || args.height == Some(0) {
+        return Err(Error::msg("width and height must be greater than 0"));
+    }
+
+    let template = FilenameTemplate::parse(&args.name)?;
+
+    ThreadPoolBuilder::new()
+        .num_threads(args.jobs)
+        .build_global()?;
+
+    let inputs = collect_inputs(&args.inputs)?;
+    if inputs.is_empty() {
+        return Err(Error::msg("no images found"));
+    }
+
+    let names = Mutex::new(UniqueNames::default());
+    let failed = AtomicUsize::new(0);
+
+    // a broken file is reported and skipped, the others still get written
+    inputs
+        .par_iter()
+        .for_each(|input| match process(input, args, &template, &names) {
+            Ok(outputs) => println!("{}: {} outputs", input.path.display(), outputs),
+            Err(e) => {
+                eprintln!("{}: {:#}", input.path.display(), e);
+                failed.fetch_add(1, Ordering::Relaxed);
+            }
+        });
+
+    match failed.into_inner() {
+        0 => Ok(()),
+        n => Err(Error::msg(format!(
+            "{} of {} images failed",
+            n,
+            inputs.len()
+        ))),
+    }
+}
+
+fn process(
+    input: &Input,
+    args: &Args,
+    template: &FilenameTemplate,
+    names: &Mutex<UniqueNames>,
+) -> Result<usize> {
+    let reader = ImageReader::open(&input.path)?.with_guessed_format()?;
+    let source_format = reader.format();
+    let mut image = reader.decode()?;
+
+    let format = args
+        .format
+        .or(source_format.filter(|f| ENCODE_FORMATS.contains(f)))
+        .unwrap_or(ImageFormat::Png);
+
+    // jpeg has no alpha
+    if format == ImageFormat::Jpeg && image.color().has_alpha() {
+        image = DynamicImage::ImageRgb8(image.to_rgb8());
+    }
+
+    let stem = input
+        .path
+        .file_stem()
+        .and_then(|n| n.to_str())
+        .unwrap_or("image");
+    let ext = format.extensions_str()[0];
+
+    let out_dir = PathBuf::from(args.out.replace("{dir}", &input.dir));
+    fs::create_dir_all(&out_dir).with_context(|| format!("create {}", out_dir.display()))?;
+
+    let frame = Frame::from_image(&image)?;
+    let targets = targets(args, (image.width(), image.height()));
+
+    targets.par_iter().enumerate().try_for_each(|(i, target)| {
+        let (width, height) = target.size;
+        let buf = frame
+            .resize_cropped(width, height, target.crop)?
+            .encode_with_quality(format, args.quality)?;
+
+        let name = template.render(&FileVars {
+            name: stem,
+            width,
+            height,
+            scale: target.scale,
+            format: ext,
+            index: i + 1,
+        });
+        let path = names
+            .lock()
+            .unwrap()
+            .claim(out_dir.join(name).to_string_lossy().into_owned());
+
+        fs::write(&path, &buf).with_context(|| format!("write {}", path))
+    })?;
+
+    Ok(targets.len())
+}
+
+/// every scale, then the box
+fn targets(args: &Args, source: (u32, u32)) -> Vec<Target> {
+    let mut targets: Vec<Target> = args
+        .scale
+        .iter()
+        .map(|&scale| Target {
+            scale,
+            size: algorithm::target_size(source.0, source.1, scale),
+            crop: None,
+        })
+        .collect();
+
+    if args.width.is_some() || args.height.is_some() {
+        let (size, crop) = algorithm::fit_size(source, args.width, args.height, args.fit);
+        targets.push(Target {
+            scale: size.0 as f32 / source.0 as f32,
+            size,
+            crop,
+        });
+    }
+
+    targets
+}
+
+fn collect_inputs(patterns: &[String]) -> Result<Vec<Input>> {
+    let mut inputs = vec![];
+
+    for pattern in patterns {
+        let path = Path::new(pattern);
+
+        if path.is_dir() {
+            walk(path, path, &mut inputs)?;
+        } else if path.is_file() {
+            inputs.push(Input {
+                path: path.to_path_buf(),
+                dir: String::new(),
+            });
+        } else if pattern.contains(['*', '?', '[']) {
+            // folders are kept below the part of the glob without wildcards
+            let root: PathBuf = path
+                .components()
+                .take_while(|c| !c.as_os_str().to_string_lossy().contains(['*', '?', '[']))
+                .collect();
+
+            for entry in glob::glob(pattern)? {
+                let entry = entry?;
+                if entry.is_file() && is_image(&entry) {
+                    inputs.push(Input {
+                        dir: relative_dir(&root, &entry),
+                        path: entry,
+                    });
+                }
+            }
+        } else {
+            return Err(Error::msg(format!("{} not found", pattern)));
+        }
+    }
+
+    Ok(inputs)
+}
+
+/// images below `dir`, hidden files and folders are skipped
+fn walk(root: &Path, dir: &Path, inputs: &mut Vec<Input>) -> Result<()> {
+    let mut entries = fs::read_dir(dir)
+        .with_context(|| format!("read {}", dir.display()))?
+        .collect::<Result<Vec<_>, _>>()?;
+    entries.sort_by_key(|e| e.file_name());
+
+    for entry in entries {
+        if entry.file_name().to_string_lossy().starts_with('.') {
+            continue;
+        }
+
+        let path = entry.path();
+        if path.is_dir() {
+            walk(root, &path, inputs)?;
+        } else if is_image(&path) {
+            inputs.push(Input {
+                dir: relative_dir(root, &path),
+                path,
+            });
+        }
+    }
+
+    Ok(())
+}
+
+fn is_image(path: &Path) -> bool {
+    ImageFormat::from_path(path).is_ok()
+}
+
+fn relative_dir(root: &Path, path: &Path) -> String {
+    path.parent()
+        .and_then(|p| p.strip_prefix(root).ok())
+        .map(|p| p.to_string_lossy().replace('\\', "/"))
+        .unwrap_or_default()
+}
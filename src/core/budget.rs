@@ -0,0 +1,239 @@
+//! memory budget for decode and resize work
+//!
+//! work is admitted by the pixels it will hold rather than by request count, so a burst of
+//! large uploads queues instead of exhausting memory
+
+use std::{
+    env,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc, OnceLock,
+    },
+    time::{Duration, Instant},
+};
+
+use image::ColorType;
+use image_resize_core::Frame;
+use poem_openapi::Object;
+use serde_json::json;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tracing::{info, warn};
+
+use crate::status_code::{AppError, AppResult, ErrorCode};
+
+const MB: u64 = 1024 * 1024;
+
+/// waits longer than this are logged
+const SLOW_WAIT: Duration = Duration::from_secs(1);
+
+static BUDGET: OnceLock<Budget> = OnceLock::new();
+
+static ADMITTED: AtomicU64 = AtomicU64::new(0);
+static REJECTED: AtomicU64 = AtomicU64::new(0);
+static WAITED: AtomicU64 = AtomicU64::new(0);
+static WAIT_MS_TOTAL: AtomicU64 = AtomicU64::new(0);
+static WAIT_MS_MAX: AtomicU64 = AtomicU64::new(0);
+
+struct Budget {
+    /// one permit per MiB
+    semaphore: Arc<Semaphore>,
+    capacity_mb: u32,
+    max_queue: usize,
+    max_wait: Duration,
+    /// requests waiting for room
+    queued: AtomicUsize,
+    /// jobs waiting for room, they never give up
+    background: AtomicUsize,
+}
+
+/// RESIZE_MEMORY_MB of estimated pixels in flight, 1024 by default, RESIZE_QUEUE_MAX
+/// requests wait for room, 64 by default, each at most RESIZE_QUEUE_WAIT_SECS, 30 by default
+fn get_budget() -> &'static Budget {
+    BUDGET.get_or_init(|| {
+        let capacity_mb = env::var("RESIZE_MEMORY_MB")
+            .ok()
+            .and_then(|v| v.parse::<u32>().ok())
+            .filter(|mb| *mb > 0)
+            .unwrap_or(1024);
+
+        Budget {
+            semaphore: Arc::new(Semaphore::new(capacity_mb as usize)),
+            capacity_mb,
+            max_queue: env::var("RESIZE_QUEUE_MAX")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(64),
+            max_wait: env::var("RESIZE_QUEUE_WAIT_SECS")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .map(Duration::from_secs)
+                .unwrap_or(Duration::from_secs(30)),
+            queued: AtomicUsize::new(0),
+            background: AtomicUsize::new(0),
+        }
+    })
+}
+
+/// room held in the budget, given back on drop
+pub struct Permit {
+    _permit: OwnedSemaphorePermit,
+}
+
+fn bytes(size: (u32, u32), color: ColorType) -> u64 {
+    size.0 as u64 * size.1 as u64 * color.bytes_per_pixel() as u64
+}
+
+/// bytes one image holds while it is resized: the decoded source, its 8 bit copy for f32
+/// sources, and the frame, then every output as a frame, as the unpremultiplied copy
+/// encoding makes when the frame has alpha, and encoded
+pub fn estimate(
+    source: (u32, u32),
+    color: ColorType,
+    outputs: impl IntoIterator<Item = (u32, u32)>,
+) -> u64 {
+    let frame = Frame::color_for(color);
+    let converted = if frame == color {
+        0
+    } else {
+        bytes(source, frame)
+    };
+    let copies = if frame.has_alpha() { 3 } else { 2 };
+
+    bytes(source, color)
+        + converted
+        + bytes(source, frame)
+        + outputs
+            .into_iter()
+            .map(|size| copies * bytes(size, frame))
+            .sum::<u64>()
+}
+
+/// bytes an encoded output of a `color` image is counted as, its pixels since lossless
+/// encodings of noise don't get smaller
+pub fn encoded(size: (u32, u32), color: ColorType) -> u64 {
+    bytes(size, Frame::color_for(color))
+}
+
+/// whole MiB, work larger than the budget runs alone instead of never
+fn permits(budget: &Budget, bytes: u64) -> u32 {
+    bytes.div_ceil(MB).clamp(1, budget.capacity_mb as u64) as u32
+}
+
+/// wait for room for `bytes`, a full queue or a wait over the limit is rejected with 503
+pub async fn acquire(bytes: u64) -> AppResult<Permit> {
+    let budget = get_budget();
+    let n = permits(budget, bytes);
+
+    if let Ok(permit) = budget.semaphore.clone().try_acquire_many_owned(n) {
+        ADMITTED.fetch_add(1, Ordering::Relaxed);
+        return Ok(Permit { _permit: permit });
+    }
+
+    if budget.queued.fetch_add(1, Ordering::AcqRel) >= budget.max_queue {
+        budget.queued.fetch_sub(1, Ordering::AcqRel);
+        return Err(overloaded("image work queue is full", budget));
+    }
+
+    let start = Instant::now();
+    let r = tokio::time::timeout(
+        budget.max_wait,
+        budget.semaphore.clone().acquire_many_owned(n),
+    )
+    .await;
+    budget.queued.fetch_sub(1, Ordering::AcqRel);
+    record_wait(start.elapsed(), n);
+
+    match r {
+        Ok(Ok(permit)) => {
+            ADMITTED.fetch_add(1, Ordering::Relaxed);
+            Ok(Permit { _permit: permit })
+        }
+        Ok(Err(_)) => Err(AppError::new(
+            ErrorCode::Internal,
+            "image work budget closed",
+        )),
+        Err(_) => Err(overloaded(
+            "timed out waiting for image work capacity",
+            budget,
+        )),
+    }
+}
+
+/// wait for room for `bytes` however long it takes, for jobs that are already queued
+pub async fn wait(bytes: u64) -> Permit {
+    let budget = get_budget();
+    let n = permits(budget, bytes);
+
+    budget.background.fetch_add(1, Ordering::AcqRel);
+    let start = Instant::now();
+    let permit = budget.semaphore.clone().acquire_many_owned(n).await;
+    budget.background.fetch_sub(1, Ordering::AcqRel);
+    record_wait(start.elapsed(), n);
+
+    ADMITTED.fetch_add(1, Ordering::Relaxed);
+    Permit {
+        _permit: permit.expect("image work budget is never closed"),
+    }
+}
+
+fn record_wait(waited: Duration, mb: u32) {
+    let ms = waited.as_millis() as u64;
+    WAITED.fetch_add(1, Ordering::Relaxed);
+    WAIT_MS_TOTAL.fetch_add(ms, Ordering::Relaxed);
+    WAIT_MS_MAX.fetch_max(ms, Ordering::Relaxed);
+
+    if waited >= SLOW_WAIT {
+        info!("image work of {} MiB waited {} ms for the budget", mb, ms);
+    }
+}
+
+fn overloaded(msg: &str, budget: &Budget) -> AppError {
+    REJECTED.fetch_add(1, Ordering::Relaxed);
+    warn!(
+        "{}, {} requests queued",
+        msg,
+        budget.queued.load(Ordering::Relaxed)
+    );
+
+    AppError::new(ErrorCode::Overloaded, msg).with_details(json!({
+        "queued": budget.queued.load(Ordering::Relaxed),
+        "retry_after": budget.max_wait.as_secs().max(1),
+    }))
+}
+
+#[derive(Object)]
+pub struct BudgetStats {
+    /// RESIZE_MEMORY_MB
+    pub capacity_mb: u32,
+    /// estimated MiB held by running work
+    pub in_use_mb: u32,
+    /// requests waiting for room
+    pub queued: usize,
+    pub max_queue: usize,
+    /// jobs waiting for room
+    pub background: usize,
+    pub admitted: u64,
+    /// requests turned away with 503
+    pub rejected: u64,
+    /// work that had to wait
+    pub waited: u64,
+    pub wait_ms_total: u64,
+    pub wait_ms_max: u64,
+}
+
+pub fn stats() -> BudgetStats {
+    let budget = get_budget();
+
+    BudgetStats {
+        capacity_mb: budget.capacity_mb,
+        in_use_mb: budget.capacity_mb - budget.semaphore.available_permits() as u32,
+        queued: budget.queued.load(Ordering::Relaxed),
+        max_queue: budget.max_queue,
+        background: budget.background.load(Ordering::Relaxed),
+        admitted: ADMITTED.load(Ordering::Relaxed),
+        rejected: REJECTED.load(Ordering::Relaxed),
+        waited: WAITED.load(Ordering::Relaxed),
+        wait_ms_total: WAIT_MS_TOTAL.load(Ordering::Relaxed),
+        wait_ms_max: WAIT_MS_MAX.load(Ordering::Relaxed),
+    }
+}
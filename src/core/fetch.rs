@@ -0,0 +1,347 @@
+use std::{
+    env,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::OnceLock,
+    time::Duration,
+};
+
+use anyhow::{Error, Result};
+use bytes::{Bytes, BytesMut};
+use reqwest::{redirect, Client, StatusCode};
+use url::{Host, Url};
+
+use crate::status_code::{AppError, ErrorCode};
+
+static POLICY: OnceLock<FetchPolicy> = OnceLock::new();
+
+/// rules for client supplied urls, `source_url` downloads and webhook callbacks
+#[derive(Clone, Debug)]
+pub struct FetchPolicy {
+    pub schemes: Vec<String>,
+    /// loopback, private and link-local targets, only for local stubs
+    pub allow_private: bool,
+    pub max_bytes: usize,
+    pub timeout: Duration,
+}
+
+impl FetchPolicy {
+    /// FETCH_SCHEMES (comma separated), FETCH_ALLOW_PRIVATE, FETCH_MAX_BYTES, FETCH_TIMEOUT_SECS
+    pub fn from_env() -> Self {
+        let schemes = env::var("FETCH_SCHEMES")
+            .unwrap_or_else(|_| "https,http".to_string())
+            .split(',')
+            .map(|s| s.trim().to_lowercase())
+            .filter(|s| !s.is_empty())
+            .collect();
+
+        let allow_private = env::var("FETCH_ALLOW_PRIVATE").is_ok_and(|v| v == "true" || v == "1");
+
+        let max_bytes = env::var("FETCH_MAX_BYTES")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(20 * 1024 * 1024);
+
+        let timeout = env::var("FETCH_TIMEOUT_SECS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(10);
+
+        FetchPolicy {
+            schemes,
+            allow_private,
+            max_bytes,
+            timeout: Duration::from_secs(timeout),
+        }
+    }
+}
+
+pub fn get_policy() -> &'static FetchPolicy {
+    POLICY.get_or_init(FetchPolicy::from_env)
+}
+
+/// download `url` under `policy`, redirects are refused
+pub async fn fetch(url: &str, policy: &FetchPolicy) -> Result<Bytes> {
+    let url = Url::parse(url)?;
+
+    let addrs = resolve(&url, policy).await?;
+    let client = pinned_client(&url, &addrs, policy)?;
+
+    let mut resp = client.get(url.clone()).send().await?;
+
+    if resp.status() != StatusCode::OK {
+        return Err(Error::msg(format!(
+            "source_url response status: {}",
+            resp.status()
+        )));
+    }
+
+    if resp
+        .content_length()
+        .is_some_and(|len| len > policy.max_bytes as u64)
+    {
+        return Err(too_large());
+    }
+
+    let mut buf = BytesMut::new();
+    while let Some(chunk) = resp.chunk().await? {
+        if buf.len() + chunk.len() > policy.max_bytes {
+            return Err(too_large());
+        }
+        buf.extend_from_slice(&chunk);
+    }
+
+    Ok(buf.freeze())
+}
+
+fn too_large() -> Error {
+    AppError::new(ErrorCode::PayloadTooLarge, "source_url is too large").into()
+}
+
+/// addresses of `url` once its scheme and every resolved address pass `policy`
+pub async fn resolve(url: &Url, policy: &FetchPolicy) -> Result<Vec<SocketAddr>> {
+    if !policy.schemes.iter().any(|s| s == url.scheme()) {
+        return Err(Error::msg(format!(
+            "url scheme {} is not allowed",
+            url.scheme()
+        )));
+    }
+
+    let host = url.host().ok_or_else(|| Error::msg("url has no host"))?;
+    let port = url
+        .port_or_known_default()
+        .ok_or_else(|| Error::msg("url has no port"))?;
+
+    let addrs: Vec<SocketAddr> = match host {
+        Host::Ipv4(ip) => vec![SocketAddr::new(IpAddr::V4(ip), port)],
+        Host::Ipv6(ip) => vec![SocketAddr::new(IpAddr::V6(ip), port)],
+        Host::Domain(domain) => {
+            tokio::time::timeout(policy.timeout, tokio::net::lookup_host((domain, port)))
+                .await??
+                .collect()
+        }
+    };
+
+    if addrs.is_empty() {
+        return Err(Error::msg("url host does not resolve"));
+    }
+
+    if !policy.allow_private && addrs.iter().any(|a| !is_public(a.ip())) {
+        return Err(Error::msg("url points to a private address"));
+    }
+
+    Ok(addrs)
+}
+
+/// client for `url` pinned to the checked `addrs`, so a second dns answer can't point it
+/// somewhere else, redirects are refused
+pub fn pinned_client(url: &Url, addrs: &[SocketAddr], policy: &FetchPolicy) -> Result<Client> {
+    let mut builder = reqwest::ClientBuilder::new()
+        .timeout(policy.timeout)
+        .connect_timeout(policy.timeout)
+        .redirect(redirect::Policy::none())
+        .no_proxy();
+    if let (Some(Host::Domain(domain)), Some(addr)) = (url.host(), addrs.first()) {
+        builder = builder.resolve(domain, *addr);
+    }
+
+    Ok(builder.build()?)
+}
+
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => is_public_v4(ip),
+        IpAddr::V6(ip) => match embedded_v4(ip) {
+            Some(v4) => is_public_v4(v4),
+            None => is_public_v6(ip),
+        },
+    }
+}
+
+/// the ipv4 address a translated or tunneled ipv6 address reaches
+fn embedded_v4(ip: Ipv6Addr) -> Option<Ipv4Addr> {
+    let s = ip.segments();
+    let v4 = |hi: u16, lo: u16| Ipv4Addr::from(((hi as u32) << 16) | lo as u32);
+
+    match s {
+        // ::ffff:0:0/96 mapped and ::/96 compatible
+        [0, 0, 0, 0, 0, 0xffff | 0, ..] => ip.to_ipv4(),
+        // 64:ff9b::/96 nat64
+        [0x64, 0xff9b, 0, 0, 0, 0, hi, lo] => Some(v4(hi, lo)),
+        // 2002::/16 6to4
+        [0x2002, hi, lo, ..] => Some(v4(hi, lo)),
+        _ => None,
+    }
+}
+
+fn is_public_v4(ip: Ipv4Addr) -> bool {
+    let [a, b, ..] = ip.octets();
+
+    !(ip.is_unspecified()
+        || ip.is_loopback()
+        || ip.is_private()
+        || ip.is_link_local()
+        || ip.is_broadcast()
+        || ip.is_documentation()
+        || ip.is_multicast()
+        // 0.0.0.0/8
+        || a == 0
+        // 100.64.0.0/10 carrier grade nat
+        || (a == 100 && (64..128).contains(&b))
+        // 198.18.0.0/15 benchmarking
+        || (a == 198 && (b == 18 || b == 19))
+        // 240.0.0.0/4 reserved
+        || a >= 240)
+}
+
+fn is_public_v6(ip: Ipv6Addr) -> bool {
+    let first = ip.segments()[0];
+
+    !(ip.is_unspecified()
+        || ip.is_loopback()
+        || ip.is_multicast()
+        // fc00::/7 unique local
+        || (first & 0xfe00) == 0xfc00
+        // fe80::/10 link local
+        || (first & 0xffc0) == 0xfe80
+        // 2001:db8::/32 documentation
+        || (first == 0x2001 && ip.segments()[1] == 0x0db8)
+        // 2001::/32 teredo, the ipv4 end is obfuscated
+        || (first == 0x2001 && ip.segments()[1] == 0)
+        // 64:ff9b:1::/48 local use nat64
+        || (first == 0x64 && ip.segments()[1] == 0xff9b && ip.segments()[2] == 1))
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    use super::*;
+
+    fn policy(allow_private: bool) -> FetchPolicy {
+        FetchPolicy {
+            schemes: vec!["http".to_string()],
+            allow_private,
+            max_bytes: 1024,
+            timeout: Duration::from_millis(500),
+        }
+    }
+
+    /// answers every connection with `response`, or never when it is none
+    async fn stub(response: Option<Vec<u8>>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            loop {
+                let Ok((mut stream, _)) = listener.accept().await else {
+                    return;
+                };
+                let response = response.clone();
+                tokio::spawn(async move {
+                    let mut buf = [0; 4096];
+                    let _ = stream.read(&mut buf).await;
+                    match response {
+                        Some(response) => {
+                            let _ = stream.write_all(&response).await;
+                        }
+                        None => tokio::time::sleep(Duration::from_secs(10)).await,
+                    }
+                });
+            }
+        });
+
+        format!("http://{}/image.png", addr)
+    }
+
+    fn ok(body: &[u8], content_length: bool) -> Vec<u8> {
+        let mut response = b"HTTP/1.1 200 OK\r\nconnection: close\r\n".to_vec();
+        if content_length {
+            response.extend(format!("content-length: {}\r\n", body.len()).bytes());
+        }
+        response.extend(b"\r\n");
+        response.extend(body);
+        response
+    }
+
+    #[tokio::test]
+    async fn fetches_within_policy() {
+        let url = stub(Some(ok(b"image", true))).await;
+
+        let blob = fetch(&url, &policy(true)).await.unwrap();
+        assert_eq!(blob.as_ref(), b"image");
+    }
+
+    #[tokio::test]
+    async fn rejects_private_addresses() {
+        let url = stub(Some(ok(b"image", true))).await;
+
+        let e = fetch(&url, &policy(false)).await.unwrap_err();
+        assert!(e.to_string().contains("private"), "{}", e);
+    }
+
+    #[tokio::test]
+    async fn refuses_redirects() {
+        let url = stub(Some(
+            b"HTTP/1.1 302 Found\r\nlocation: http://169.254.169.254/\r\ncontent-length: 0\r\n\r\n"
+                .to_vec(),
+        ))
+        .await;
+
+        let e = fetch(&url, &policy(true)).await.unwrap_err();
+        assert!(e.to_string().contains("302"), "{}", e);
+    }
+
+    #[tokio::test]
+    async fn limits_size() {
+        for content_length in [true, false] {
+            let url = stub(Some(ok(&[0; 2048], content_length))).await;
+
+            let e = fetch(&url, &policy(true)).await.unwrap_err();
+            assert_eq!(
+                e.downcast_ref::<AppError>().map(|e| e.code),
+                Some(ErrorCode::PayloadTooLarge)
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn times_out() {
+        let url = stub(None).await;
+
+        let start = std::time::Instant::now();
+        assert!(fetch(&url, &policy(true)).await.is_err());
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn blocks_translated_private_addresses() {
+        for ip in [
+            "127.0.0.1",
+            "10.1.2.3",
+            "::1",
+            "::ffff:127.0.0.1",
+            "::ffff:169.254.169.254",
+            "::10.0.0.1",
+            "64:ff9b::a9fe:a9fe",
+            "64:ff9b::7f00:1",
+            "2002:7f00:1::",
+            "2002:c0a8:101::1",
+            "2001:0:4136:e378::1",
+            "fd00::1",
+        ] {
+            assert!(!is_public(ip.parse().unwrap()), "{}", ip);
+        }
+
+        for ip in [
+            "8.8.8.8",
+            "::ffff:8.8.8.8",
+            "64:ff9b::808:808",
+            "2606:4700::1111",
+        ] {
+            assert!(is_public(ip.parse().unwrap()), "{}", ip);
+        }
+    }
+}
@@ -0,0 +1,251 @@
+use std::io::Cursor;
+
+use anyhow::Result;
+use exif::{In, Tag};
+use image::{ColorType, ImageDecoder, ImageFormat, ImageReader};
+use poem_openapi::Object;
+use serde::Serialize;
+
+use crate::status_code::{AppError, ErrorCode};
+
+#[derive(Serialize, Object, Debug)]
+pub struct ImageInfo {
+    /// mime type, e.g. image/png
+    pub format: String,
+    pub width: u32,
+    pub height: u32,
+    pub color_type: String,
+    /// bits per channel
+    pub bit_depth: u16,
+    pub has_alpha: bool,
+    pub frame_count: u32,
+    pub exif: Option<ExifSummary>,
+    pub icc_profile: Option<String>,
+    /// bytes
+    pub file_size: usize,
+}
+
+#[derive(Serialize, Object, Debug, Default)]
+pub struct ExifSummary {
+    pub make: Option<String>,
+    pub model: Option<String>,
+    pub software: Option<String>,
+    pub orientation: Option<String>,
+    pub date_time_original: Option<String>,
+    pub exposure_time: Option<String>,
+    pub f_number: Option<String>,
+    pub iso: Option<String>,
+    pub focal_length: Option<String>,
+    pub lens_model: Option<String>,
+    pub has_gps: bool,
+    pub tag_count: usize,
+}
+
+/// dimensions and color type from the header, enough to budget a decode before it runs
+pub fn header(blob: &[u8]) -> Result<((u32, u32), ColorType)> {
+    let reader = ImageReader::new(Cursor::new(blob)).with_guessed_format()?;
+
+    if reader.format().is_none() {
+        return Err(unknown_format().into());
+    }
+
+    let decoder = reader.into_decoder()?;
+
+    Ok((decoder.dimensions(), decoder.color_type()))
+}
+
+/// reads headers only, pixel data is never decoded
+pub fn inspect(blob: &[u8]) -> Result<ImageInfo> {
+    let reader = ImageReader::new(Cursor::new(blob)).with_guessed_format()?;
+
+    let format = reader.format().ok_or_else(unknown_format)?;
+
+    let mut decoder = reader.into_decoder()?;
+
+    let (width, height) = decoder.dimensions();
+    let color_type = decoder.color_type();
+
+    let exif = decoder
+        .exif_metadata()
+        .ok()
+        .flatten()
+        .and_then(summarize_exif);
+
+    let icc_profile = decoder
+        .icc_profile()
+        .ok()
+        .flatten()
+        .and_then(|icc| icc_description(&icc));
+
+    Ok(ImageInfo {
+        format: format.to_mime_type().to_string(),
+        width,
+        height,
+        color_type: format!("{:?}", color_type),
+        bit_depth: color_type.bits_per_pixel() / color_type.channel_count() as u16,
+        has_alpha: color_type.has_alpha(),
+        frame_count: frame_count(blob, format),
+        exif,
+        icc_profile,
+        file_size: blob.len(),
+    })
+}
+
+fn summarize_exif(raw: Vec<u8>) -> Option<ExifSummary> {
+    // jpeg app1 payloads may still carry the "Exif\0\0" marker
+    let raw = match raw.strip_prefix(b"Exif\0\0") {
+        Some(tiff) => tiff.to_vec(),
+        None => raw,
+    };
+
+    let exif = exif::Reader::new().read_raw(raw).ok()?;
+
+    let field = |tag: Tag| {
+        exif.get_field(tag, In::PRIMARY).map(|f| {
+            f.display_value()
+                .with_unit(&exif)
+                .to_string()
+                .trim_matches('"')
+                .to_string()
+        })
+    };
+
+    let has_gps = exif
+        .fields()
+        .any(|f| f.ifd_num == In::PRIMARY && f.tag.context() == exif::Context::Gps);
+
+    let tag_count = exif.fields().len();
+
+    let summary = ExifSummary {
+        make: field(Tag::Make),
+        model: field(Tag::Model),
+        software: field(Tag::Software),
+        orientation: field(Tag::Orientation),
+        date_time_original: field(Tag::DateTimeOriginal),
+        exposure_time: field(Tag::ExposureTime),
+        f_number: field(Tag::FNumber),
+        iso: field(Tag::PhotographicSensitivity),
+        focal_length: field(Tag::FocalLength),
+        lens_model: field(Tag::LensModel),
+        has_gps,
+        tag_count,
+    };
+
+    Some(summary)
+}
+
+/// profile description from the `desc` tag, v2 `desc` and v4 `mluc` layouts
+fn icc_description(icc: &[u8]) -> Option<String> {
+    let be_u32 = |at: usize| -> Option<u32> {
+        icc.get(at..at + 4)
+            .map(|b| u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+    };
+
+    let tag_count = be_u32(128)? as usize;
+
+    for i in 0..tag_count {
+        let entry = 132 + i * 12;
+        if icc.get(entry..entry + 4)? != b"desc" {
+            continue;
+        }
+
+        let offset = be_u32(entry + 4)? as usize;
+        let size = be_u32(entry + 8)? as usize;
+        let data = icc.get(offset..offset.checked_add(size)?)?;
+
+        return match data.get(0..4)? {
+            b"desc" => {
+                let len = u32::from_be_bytes(data.get(8..12)?.try_into().ok()?) as usize;
+                let ascii = data.get(12..12 + len)?;
+                let text = String::from_utf8_lossy(ascii);
+                Some(text.trim_end_matches('\0').to_string())
+            }
+            b"mluc" => {
+                let records = u32::from_be_bytes(data.get(8..12)?.try_into().ok()?);
+                if records == 0 {
+                    return None;
+                }
+                // first record, usually en-US
+                let len = u32::from_be_bytes(data.get(20..24)?.try_into().ok()?) as usize;
+                let at = u32::from_be_bytes(data.get(24..28)?.try_into().ok()?) as usize;
+                let utf16: Vec<u16> = data
+                    .get(at..at + len)?
+                    .chunks_exact(2)
+                    .map(|c| u16::from_be_bytes([c[0], c[1]]))
+                    .collect();
+                Some(
+                    String::from_utf16_lossy(&utf16)
+                        .trim_end_matches('\0')
+                        .to_string(),
+                )
+            }
+            _ => None,
+        };
+    }
+
+    None
+}
+
+fn unknown_format() -> AppError {
+    AppError::new(
+        ErrorCode::UnsupportedFormat,
+        "upload image format is unkown",
+    )
+}
+
+/// counted from the container chunks, animations are not decoded
+fn frame_count(blob: &[u8], format: ImageFormat) -> u32 {
+    let frames = match format {
+        ImageFormat::Png => png_frame_count(blob),
+        ImageFormat::WebP => webp_frame_count(blob),
+        _ => None,
+    };
+
+    frames.unwrap_or(1).max(1)
+}
+
+fn png_frame_count(blob: &[u8]) -> Option<u32> {
+    // 8 bytes signature, then length + type + data + crc chunks
+    let mut at = 8;
+    while at + 8 <= blob.len() {
+        let len = u32::from_be_bytes(blob[at..at + 4].try_into().ok()?) as usize;
+        let kind = &blob[at + 4..at + 8];
+
+        match kind {
+            // acTL: num_frames, num_plays
+            b"acTL" => {
+                let data = blob.get(at + 8..at + 12)?;
+                return Some(u32::from_be_bytes(data.try_into().ok()?));
+            }
+            // acTL must appear before the first IDAT
+            b"IDAT" | b"IEND" => return None,
+            _ => {}
+        }
+
+        at = at.checked_add(12 + len)?;
+    }
+
+    None
+}
+
+fn webp_frame_count(blob: &[u8]) -> Option<u32> {
+    // RIFF header is 12 bytes, chunks are fourcc + le size + padded data
+    let mut at = 12;
+    let mut frames = 0;
+    while at + 8 <= blob.len() {
+        let kind = &blob[at..at + 4];
+        let len = u32::from_le_bytes(blob[at + 4..at + 8].try_into().ok()?) as usize;
+
+        if kind == b"ANMF" {
+            frames += 1;
+        }
+
+        at = at.checked_add(8 + len + (len & 1))?;
+    }
+
+    if frames == 0 {
+        None
+    } else {
+        Some(frames)
+    }
+}
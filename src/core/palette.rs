@@ -0,0 +1,240 @@
+use std::cmp::Reverse;
+
+use image::{imageops::FilterType, DynamicImage};
+use poem_openapi::Object;
+use serde::Serialize;
+
+/// max edge of the sample the palette is computed from, keeps big uploads cheap
+const SAMPLE_EDGE: u32 = 128;
+
+/// pixels more transparent than this are not counted
+const ALPHA_THRESHOLD: u8 = 128;
+
+/// k-means passes over the median cut colors before populations are counted
+const REFINE_ROUNDS: usize = 4;
+
+pub const DEFAULT_PALETTE_SIZE: usize = 5;
+pub const MAX_PALETTE_SIZE: usize = 32;
+
+#[derive(Serialize, Object, Debug, Clone)]
+pub struct PaletteColor {
+    /// #rrggbb
+    pub hex: String,
+    /// pixel count in the sampled image
+    pub population: u32,
+    /// population / total sampled pixels
+    pub proportion: f32,
+}
+
+#[derive(Serialize, Object, Debug, Clone)]
+pub struct Palette {
+    pub dominant: Option<PaletteColor>,
+    pub colors: Vec<PaletteColor>,
+}
+
+/// median cut palette, colors are sorted by population desc
+pub fn extract(image: &DynamicImage, count: usize) -> Palette {
+    let count = count.clamp(1, MAX_PALETTE_SIZE);
+
+    let sample = if image.width() > SAMPLE_EDGE || image.height() > SAMPLE_EDGE {
+        image.resize(SAMPLE_EDGE, SAMPLE_EDGE, FilterType::Triangle)
+    } else {
+        image.clone()
+    };
+
+    let pixels: Vec<[u8; 3]> = sample
+        .to_rgba8()
+        .pixels()
+        .filter(|p| p[3] >= ALPHA_THRESHOLD)
+        .map(|p| [p[0], p[1], p[2]])
+        .collect();
+
+    let total = pixels.len();
+    if total == 0 {
+        return Palette {
+            dominant: None,
+            colors: vec![],
+        };
+    }
+
+    let mut buckets = vec![pixels];
+
+    while buckets.len() < count {
+        // split the bucket with the widest channel range, ties go to the bigger one
+        let target = buckets
+            .iter()
+            .enumerate()
+            .filter(|(_, b)| b.len() > 1)
+            .map(|(i, b)| {
+                let (_, range) = widest_channel(b);
+                (i, range as usize * b.len())
+            })
+            .filter(|(_, score)| *score > 0)
+            .max_by_key(|(_, score)| *score);
+
+        let Some((i, _)) = target else {
+            break;
+        };
+
+        let mut bucket = buckets.swap_remove(i);
+        let (channel, _) = widest_channel(&bucket);
+        bucket.sort_unstable_by_key(|p| p[channel]);
+        let upper = bucket.split_off(bucket.len() / 2);
+        buckets.push(bucket);
+        buckets.push(upper);
+    }
+
+    // median cut splits at the median, so every bucket holds about as many samples,
+    // refine its averages k-means style and count each pixel at its nearest color
+    let mut centers: Vec<[u8; 3]> = buckets.iter().map(|b| average(b)).collect();
+    let mut populations = vec![0u32; centers.len()];
+    let pixels = pixels_of(&buckets);
+
+    for round in 0..=REFINE_ROUNDS {
+        let mut sums = vec![[0u64; 3]; centers.len()];
+        populations.fill(0);
+
+        for p in &pixels {
+            let i = nearest(&centers, p);
+            populations[i] += 1;
+            for c in 0..3 {
+                sums[i][c] += p[c] as u64;
+            }
+        }
+
+        if round == REFINE_ROUNDS {
+            break;
+        }
+        for (i, sum) in sums.iter().enumerate() {
+            let n = populations[i] as u64;
+            if n > 0 {
+                centers[i] = sum.map(|s| ((s + n / 2) / n) as u8);
+            }
+        }
+
+        // ties go to the first center, so a duplicate ends up empty like any other
+        // center nobody is nearest to, it restarts at the worst served pixel
+        for i in 0..centers.len() {
+            if populations[i] > 0 {
+                continue;
+            }
+            let farthest = pixels
+                .iter()
+                .map(|p| (p, distance(&centers[nearest(&centers, p)], p)))
+                .max_by_key(|(_, d)| *d);
+            if let Some((p, _)) = farthest.filter(|(_, d)| *d > 0) {
+                centers[i] = *p;
+            }
+        }
+    }
+
+    let mut colors: Vec<PaletteColor> = centers
+        .iter()
+        .zip(&populations)
+        .filter(|(_, population)| **population > 0)
+        .map(|(avg, population)| PaletteColor {
+            hex: format!("#{:02x}{:02x}{:02x}", avg[0], avg[1], avg[2]),
+            population: *population,
+            proportion: *population as f32 / total as f32,
+        })
+        .collect();
+
+    colors.sort_by_key(|c| Reverse(c.population));
+
+    Palette {
+        dominant: colors.first().cloned(),
+        colors,
+    }
+}
+
+fn pixels_of(buckets: &[Vec<[u8; 3]>]) -> Vec<[u8; 3]> {
+    buckets.iter().flatten().copied().collect()
+}
+
+fn average(pixels: &[[u8; 3]]) -> [u8; 3] {
+    let mut sum = [0u64; 3];
+    for p in pixels {
+        for c in 0..3 {
+            sum[c] += p[c] as u64;
+        }
+    }
+    let n = pixels.len().max(1) as u64;
+    sum.map(|s| ((s + n / 2) / n) as u8)
+}
+
+/// index of the center closest to `p`, the first of equally close ones
+fn nearest(centers: &[[u8; 3]], p: &[u8; 3]) -> usize {
+    centers
+        .iter()
+        .enumerate()
+        .min_by_key(|(_, center)| distance(center, p))
+        .map_or(0, |(i, _)| i)
+}
+
+/// squared distance in rgb space
+fn distance(a: &[u8; 3], b: &[u8; 3]) -> i32 {
+    (0..3).map(|c| (a[c] as i32 - b[c] as i32).pow(2)).sum()
+}
+
+fn widest_channel(pixels: &[[u8; 3]]) -> (usize, u8) {
+    let mut min = [u8::MAX; 3];
+    let mut max = [u8::MIN; 3];
+    for p in pixels {
+        for c in 0..3 {
+            min[c] = min[c].min(p[c]);
+            max[c] = max[c].max(p[c]);
+        }
+    }
+
+    (0..3)
+        .map(|c| (c, max[c] - min[c]))
+        .max_by_key(|(_, range)| *range)
+        .unwrap_or((0, 0))
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{Rgb, RgbImage};
+
+    use super::*;
+
+    fn hexes(palette: &Palette) -> Vec<&str> {
+        palette.colors.iter().map(|c| c.hex.as_str()).collect()
+    }
+
+    #[test]
+    fn two_colors_give_two_distinct_entries() {
+        // 3 to 1, the median splits land inside the red run and repeat it
+        let image = RgbImage::from_fn(16, 16, |x, _| {
+            if x < 12 {
+                Rgb([255, 0, 0])
+            } else {
+                Rgb([0, 0, 255])
+            }
+        });
+
+        let palette = extract(&DynamicImage::ImageRgb8(image), 4);
+
+        assert_eq!(hexes(&palette), ["#ff0000", "#0000ff"]);
+        assert_eq!(palette.colors[0].population, 192);
+        assert_eq!(palette.colors[1].population, 64);
+        assert_eq!(palette.dominant.unwrap().hex, "#ff0000");
+    }
+
+    #[test]
+    fn empty_clusters_move_to_unserved_colors() {
+        // mostly red, 8 pixels each of blue, green and white, median cut averages blue
+        // and white into one bucket and leaves a red duplicate
+        let rare = [[0, 0, 255], [0, 255, 0], [255, 255, 255]];
+        let image = RgbImage::from_fn(16, 16, |x, y| {
+            let i = (y * 16 + x) as usize / 8;
+            Rgb(*rare.get(i).unwrap_or(&[255, 0, 0]))
+        });
+
+        let palette = extract(&DynamicImage::ImageRgb8(image), 4);
+        let mut found = hexes(&palette);
+        found.sort_unstable();
+
+        assert_eq!(found, ["#0000ff", "#00ff00", "#ff0000", "#ffffff"]);
+    }
+}
@@ -0,0 +1,53 @@
+use std::{
+    env,
+    panic::{self, AssertUnwindSafe},
+    sync::OnceLock,
+};
+
+use anyhow::{Error, Result};
+use rayon::{ThreadPool, ThreadPoolBuilder};
+use tokio::sync::oneshot;
+
+static POOL: OnceLock<ThreadPool> = OnceLock::new();
+
+/// decode / resize / encode run here so they never block a tokio worker,
+/// size with RESIZE_THREADS, defaults to the number of cpus
+fn get_pool() -> &'static ThreadPool {
+    POOL.get_or_init(|| {
+        let threads = env::var("RESIZE_THREADS")
+            .ok()
+            .and_then(|v| v.parse::<usize>().ok())
+            .unwrap_or(0);
+
+        ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .thread_name(|i| format!("resize-{}", i))
+            .build()
+            .expect("build resize thread pool")
+    })
+}
+
+/// threads of the resize pool
+pub fn threads() -> usize {
+    get_pool().current_num_threads()
+}
+
+/// run cpu bound work on the resize pool, `rayon::iter` calls inside `f` share the same pool
+pub async fn run<F, T>(f: F) -> Result<T>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    let (tx, rx) = oneshot::channel();
+
+    get_pool().spawn(move || {
+        let r = panic::catch_unwind(AssertUnwindSafe(f));
+        let _ = tx.send(r);
+    });
+
+    match rx.await {
+        Ok(Ok(r)) => Ok(r),
+        Ok(Err(_)) => Err(Error::msg("resize task panicked")),
+        Err(_) => Err(Error::msg("resize task dropped")),
+    }
+}
@@ -0,0 +1,72 @@
+use anyhow::{Error, Result};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use url::form_urlencoded;
+
+/// query params the signature adds, a client value for them is replaced when minting
+const SIGNING_PARAMS: [&str; 3] = ["kid", "exp", "sig"];
+
+/// path plus query without `sig`, params sorted so their order doesn't matter
+pub fn canonical(path: &str, params: &[(String, String)]) -> String {
+    let mut params: Vec<&(String, String)> = params.iter().filter(|(k, _)| k != "sig").collect();
+    params.sort();
+
+    let query = form_urlencoded::Serializer::new(String::new())
+        .extend_pairs(params)
+        .finish();
+
+    format!("{}?{}", path, query)
+}
+
+fn mac(secret: &str, canonical: &str) -> Result<Hmac<Sha256>> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes())?;
+    mac.update(canonical.as_bytes());
+    Ok(mac)
+}
+
+pub fn signature(secret: &str, canonical: &str) -> Result<String> {
+    Ok(format!(
+        "{:x}",
+        mac(secret, canonical)?.finalize().into_bytes()
+    ))
+}
+
+/// constant time compare against a hex signature
+pub fn verify(secret: &str, canonical: &str, sig: &str) -> Result<bool> {
+    let sig = decode_hex(sig).ok_or_else(|| Error::msg("sig is not hex"))?;
+    Ok(mac(secret, canonical)?.verify_slice(&sig).is_ok())
+}
+
+/// `path_and_query` with `kid`, `exp` (unix seconds) and `sig` appended
+pub fn sign_url(path_and_query: &str, kid: &str, secret: &str, exp: i64) -> Result<String> {
+    let (path, query) = path_and_query
+        .split_once('?')
+        .unwrap_or((path_and_query, ""));
+
+    let mut params: Vec<(String, String)> = form_urlencoded::parse(query.as_bytes())
+        .into_owned()
+        .filter(|(k, _)| !SIGNING_PARAMS.contains(&k.as_str()))
+        .collect();
+    params.push(("kid".to_string(), kid.to_string()));
+    params.push(("exp".to_string(), exp.to_string()));
+
+    let sig = signature(secret, &canonical(path, &params))?;
+    params.push(("sig".to_string(), sig));
+
+    let query = form_urlencoded::Serializer::new(String::new())
+        .extend_pairs(params)
+        .finish();
+
+    Ok(format!("{}?{}", path, query))
+}
+
+fn decode_hex(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) {
+        return None;
+    }
+
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok())
+        .collect()
+}
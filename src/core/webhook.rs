@@ -0,0 +1,71 @@
+use std::{env, sync::OnceLock, time::SystemTime};
+
+use anyhow::{Error, Result};
+use bytes::Bytes;
+use hmac::{Hmac, Mac};
+use reqwest::{header, StatusCode};
+use sha2::Sha256;
+use url::Url;
+
+use super::fetch;
+
+/// unix seconds the signature was made at
+pub const TIMESTAMP_HEADER: &str = "x-imgres-timestamp";
+
+/// `sha256=<hex hmac of "{timestamp}.{body}">`
+pub const SIGNATURE_HEADER: &str = "x-imgres-signature";
+
+static SECRET: OnceLock<Option<String>> = OnceLock::new();
+
+/// WEBHOOK_SECRET, callbacks are refused without it
+fn get_secret() -> Option<&'static str> {
+    SECRET
+        .get_or_init(|| env::var("WEBHOOK_SECRET").ok().filter(|s| !s.is_empty()))
+        .as_deref()
+}
+
+pub fn is_configured() -> bool {
+    get_secret().is_some()
+}
+
+/// receivers recompute this over the raw body and reject old timestamps
+pub fn sign(secret: &str, timestamp: u64, body: &[u8]) -> Result<String> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes())?;
+    mac.update(timestamp.to_string().as_bytes());
+    mac.update(b".");
+    mac.update(body);
+
+    Ok(format!("sha256={:x}", mac.finalize().into_bytes()))
+}
+
+/// a callback url must pass the same checks as `source_url`
+pub async fn check(url: &str) -> Result<()> {
+    fetch::resolve(&Url::parse(url)?, fetch::get_policy()).await?;
+    Ok(())
+}
+
+/// post one signed delivery, returns the receiver's status
+pub async fn post(url: &str, body: Bytes) -> Result<StatusCode> {
+    let secret = get_secret().ok_or_else(|| Error::msg("WEBHOOK_SECRET is not set"))?;
+
+    let url = Url::parse(url)?;
+    let policy = fetch::get_policy();
+    let addrs = fetch::resolve(&url, policy).await?;
+    let client = fetch::pinned_client(&url, &addrs, policy)?;
+
+    let timestamp = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)?
+        .as_secs();
+    let signature = sign(secret, timestamp, &body)?;
+
+    let resp = client
+        .post(url)
+        .header(header::CONTENT_TYPE, "application/json")
+        .header(TIMESTAMP_HEADER, timestamp)
+        .header(SIGNATURE_HEADER, signature)
+        .body(body)
+        .send()
+        .await?;
+
+    Ok(resp.status())
+}
@@ -1,12 +1,16 @@
 use anyhow::{Ok, Result};
 use aws_config::{timeout::TimeoutConfig, BehaviorVersion, Region};
 use aws_sdk_s3::{config::Credentials, Client};
+use bytes::Bytes;
 use std::{env, time::Duration};
 use tokio::sync::OnceCell;
 
+use crate::status_code::{AppError, AppResult, ErrorCode};
+
 static R2_CLIENT: OnceCell<Client> = OnceCell::const_new();
 static R2_PUBLIC_URL: OnceCell<String> = OnceCell::const_new();
 static R2_BUCKET: OnceCell<String> = OnceCell::const_new();
+static R2_ORIGINALS_PREFIX: OnceCell<Option<String>> = OnceCell::const_new();
 
 async fn get_client() -> &'static Client {
     R2_CLIENT
@@ -56,6 +60,41 @@ async fn get_r2_public_url() -> &'static String {
         .await
 }
 
+/// R2_ORIGINALS_PREFIX as a folder, none when unset or empty so /img never reads uploads or
+/// anything else in the bucket
+async fn get_originals_prefix() -> Option<&'static str> {
+    R2_ORIGINALS_PREFIX
+        .get_or_init(|| {
+            Box::pin(async {
+                env::var("R2_ORIGINALS_PREFIX")
+                    .ok()
+                    .map(|p| p.trim_matches('/').to_string())
+                    .filter(|p| !p.is_empty())
+                    .map(|p| format!("{}/", p))
+            })
+        })
+        .await
+        .as_deref()
+}
+
+/// object key of an /img source, `key` must stay below the originals prefix
+pub async fn original_key(key: &str) -> AppResult<String> {
+    let prefix = get_originals_prefix()
+        .await
+        .ok_or_else(|| AppError::new(ErrorCode::NotFound, "/img is not enabled"))?;
+
+    let escapes = key.contains('\\')
+        || key.chars().any(char::is_control)
+        || key
+            .split('/')
+            .any(|p| p.is_empty() || p == "." || p == "..");
+    if escapes {
+        return Err(AppError::invalid_params("source key is invalid"));
+    }
+
+    AppResult::Ok(format!("{}{}", prefix, key))
+}
+
 // resize_upload_temp
 fn get_temp_key(filename: &str) -> String {
     format!("temp/resize_upload_{}", filename)
@@ -77,3 +116,23 @@ pub async fn upload_temp(blob: Vec<u8>, filename: &str) -> Result<String> {
     let pub_url = get_r2_public_url().await;
     Ok(format!("https://{}/{}", pub_url, &key))
 }
+
+/// original served by /img, `None` when the key does not exist, see [`original_key`]
+pub async fn get_original(key: &str) -> Result<Option<Bytes>> {
+    let client: &Client = get_client().await;
+
+    let key = original_key(key).await?;
+    let r = client
+        .get_object()
+        .bucket(get_r2_buket().await)
+        .key(key)
+        .send()
+        .await;
+
+    let output = match r {
+        Err(e) if e.as_service_error().is_some_and(|e| e.is_no_such_key()) => return Ok(None),
+        r => r?,
+    };
+
+    Ok(Some(output.body.collect().await?.into_bytes()))
+}
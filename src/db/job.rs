@@ -0,0 +1,203 @@
+use std::{
+    env,
+    path::{Path, PathBuf},
+    sync::OnceLock,
+};
+
+use anyhow::{Error, Result};
+use bytes::Bytes;
+use chrono::{DateTime, Local};
+use poem_openapi::{Enum, Object};
+use serde::{Deserialize, Serialize};
+use tokio::fs;
+
+static JOBS_DIR: OnceLock<PathBuf> = OnceLock::new();
+
+const JOB_FILE: &str = "job.json";
+const REQUEST_FILE: &str = "request";
+const RESULT_FILE: &str = "result";
+
+/// JOBS_DIR, one folder per job with its state, the original request and the result
+fn get_jobs_dir() -> &'static Path {
+    JOBS_DIR.get_or_init(|| {
+        env::var("JOBS_DIR")
+            .unwrap_or_else(|_| "jobs".to_string())
+            .into()
+    })
+}
+
+#[derive(Serialize, Deserialize, Enum, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+#[oai(rename_all = "lowercase")]
+pub enum JobStatus {
+    Queued,
+    Running,
+    Done,
+    Failed,
+}
+
+impl JobStatus {
+    pub fn is_finished(&self) -> bool {
+        matches!(self, JobStatus::Done | JobStatus::Failed)
+    }
+}
+
+/// images finished for one entry of `sizes`, batches count every image
+#[derive(Serialize, Deserialize, Object, Clone, Debug)]
+pub struct SizeProgress {
+    /// 0 for a box
+    pub scale: f32,
+    pub use_ai: bool,
+    #[serde(default)]
+    pub width: Option<u32>,
+    #[serde(default)]
+    pub height: Option<u32>,
+    pub done: usize,
+    pub total: usize,
+}
+
+/// one attempt to post the completion callback
+#[derive(Serialize, Deserialize, Object, Clone, Debug)]
+pub struct Delivery {
+    pub attempt: u32,
+    pub time: DateTime<Local>,
+    /// receiver's status, `None` when the request failed
+    pub status: Option<u16>,
+    pub error: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Object, Clone, Debug)]
+pub struct Callback {
+    pub url: String,
+    pub delivered: bool,
+    pub deliveries: Vec<Delivery>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Job {
+    pub id: String,
+    pub status: JobStatus,
+    /// owner, `None` for anonymous jobs without ai sizes
+    pub email: Option<String>,
+    /// headers the request is replayed with
+    pub content_type: String,
+    pub accept: Option<String>,
+    /// content type of the result, known once the job started
+    pub result_type: Option<String>,
+    pub progress: Vec<SizeProgress>,
+    pub error: Option<String>,
+    pub credits: i64,
+    /// posted once the job finished
+    #[serde(default)]
+    pub callback: Option<Callback>,
+    pub ctime: DateTime<Local>,
+    pub mtime: DateTime<Local>,
+}
+
+impl Job {
+    pub fn new(
+        email: Option<String>,
+        content_type: String,
+        accept: Option<String>,
+        callback_url: Option<String>,
+    ) -> Self {
+        let now = Local::now();
+        Job {
+            id: uuid::Uuid::new_v4().simple().to_string(),
+            status: JobStatus::Queued,
+            email,
+            content_type,
+            accept,
+            result_type: None,
+            progress: vec![],
+            error: None,
+            credits: 0,
+            callback: callback_url.map(|url| Callback {
+                url,
+                delivered: false,
+                deliveries: vec![],
+            }),
+            ctime: now,
+            mtime: now,
+        }
+    }
+}
+
+/// ids are generated here, anything else must not reach the filesystem
+fn job_dir(id: &str) -> Result<PathBuf> {
+    if id.is_empty() || !id.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(Error::msg("job id is invalid"));
+    }
+
+    Ok(get_jobs_dir().join(id))
+}
+
+pub fn result_path(id: &str) -> Result<PathBuf> {
+    Ok(job_dir(id)?.join(RESULT_FILE))
+}
+
+pub async fn insert(job: &Job, request: &[u8]) -> Result<()> {
+    let dir = job_dir(&job.id)?;
+    fs::create_dir_all(&dir).await?;
+    fs::write(dir.join(REQUEST_FILE), request).await?;
+
+    update(job).await
+}
+
+/// replace the state file in one rename, readers never see half a file
+pub async fn update(job: &Job) -> Result<()> {
+    let dir = job_dir(&job.id)?;
+    let tmp = dir.join(format!("{}.tmp", JOB_FILE));
+
+    fs::write(&tmp, serde_json::to_vec(job)?).await?;
+    fs::rename(&tmp, dir.join(JOB_FILE)).await?;
+
+    Ok(())
+}
+
+pub async fn get(id: &str) -> Result<Option<Job>> {
+    let path = job_dir(id)?.join(JOB_FILE);
+
+    match fs::read(&path).await {
+        Ok(data) => Ok(Some(serde_json::from_slice(&data)?)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+pub async fn get_request(id: &str) -> Result<Bytes> {
+    Ok(Bytes::from(
+        fs::read(job_dir(id)?.join(REQUEST_FILE)).await?,
+    ))
+}
+
+/// the original request is only needed until the job finished
+pub async fn remove_request(id: &str) -> Result<()> {
+    fs::remove_file(job_dir(id)?.join(REQUEST_FILE)).await?;
+    Ok(())
+}
+
+pub async fn remove(id: &str) -> Result<()> {
+    fs::remove_dir_all(job_dir(id)?).await?;
+    Ok(())
+}
+
+/// every stored job, unreadable folders are skipped
+pub async fn list() -> Result<Vec<Job>> {
+    let mut jobs = vec![];
+
+    let mut dir = match fs::read_dir(get_jobs_dir()).await {
+        Ok(dir) => dir,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(jobs),
+        Err(e) => return Err(e.into()),
+    };
+
+    while let Some(entry) = dir.next_entry().await? {
+        let id = entry.file_name().to_string_lossy().to_string();
+        if let Ok(Some(job)) = get(&id).await {
+            jobs.push(job);
+        }
+    }
+
+    Ok(jobs)
+}
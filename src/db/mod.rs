@@ -1,5 +1,8 @@
 pub mod file;
+pub mod job;
 pub mod kv;
+pub mod preset;
+pub mod signing_key;
 pub mod user;
 pub mod user_opt;
 pub mod user_recharge;
@@ -0,0 +1,138 @@
+use anyhow::Result;
+use chrono::{DateTime, Local};
+use poem_openapi::Object;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::sync::{Mutex, MutexGuard};
+
+use super::kv::{self, KvReqBody};
+use crate::status_code::AppError;
+
+/// presets one user can keep
+pub const MAX_PRESETS: usize = 50;
+
+/// one entry of `sizes`
+#[derive(Serialize, Deserialize, Object, Clone, Debug)]
+#[oai(deny_unknown_fields)]
+pub struct PresetSize {
+    /// left out for a box
+    #[oai(default)]
+    #[serde(default)]
+    pub scale: f32,
+    #[oai(default)]
+    #[serde(default)]
+    pub use_ai: bool,
+    /// box to fit into instead of a scale, a missing side follows the aspect ratio
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    /// contain, cover or fill the box
+    pub fit: Option<String>,
+}
+
+/// resize options saved under a name, a request naming the preset can override each one
+#[derive(Serialize, Deserialize, Object, Clone, Debug)]
+#[oai(deny_unknown_fields)]
+pub struct PresetOptions {
+    pub sizes: Vec<PresetSize>,
+    /// png, jpeg, webp or avif
+    pub format: Option<String>,
+    /// jpeg and avif quality 1-100
+    pub quality: Option<u8>,
+    pub palette: Option<usize>,
+    pub cascade: Option<bool>,
+    pub response: Option<String>,
+    pub filename_template: Option<String>,
+    pub compression: Option<String>,
+    pub compression_level: Option<i64>,
+}
+
+#[derive(Serialize, Deserialize, Object, Clone, Debug)]
+pub struct Preset {
+    pub name: String,
+    pub options: PresetOptions,
+    pub ctime: DateTime<Local>,
+    pub mtime: DateTime<Local>,
+}
+
+/// kv has no compare-and-swap, updates of one user take turns on this instance
+static LOCKS: [Mutex<()>; 64] = [const { Mutex::const_new(()) }; 64];
+
+async fn lock(email: &str) -> MutexGuard<'static, ()> {
+    let hash = Sha256::digest(email.as_bytes());
+    LOCKS[hash[0] as usize % LOCKS.len()].lock().await
+}
+
+/// every preset of a user sits under one key, kv can't list keys
+fn gen_key(email: &str) -> String {
+    format!("presets_{:x}", Sha256::digest(email.as_bytes()))
+}
+
+/// in the order they were created
+pub async fn list(email: &str) -> Result<Vec<Preset>> {
+    let value = kv::get(&gen_key(email)).await?;
+
+    match value {
+        Some(value) => Ok(serde_json::from_str(&value)?),
+        None => Ok(vec![]),
+    }
+}
+
+pub async fn get(email: &str, name: &str) -> Result<Option<Preset>> {
+    Ok(list(email).await?.into_iter().find(|p| p.name == name))
+}
+
+/// create or replace
+pub async fn put(email: &str, name: &str, options: PresetOptions) -> Result<Preset> {
+    let _guard = lock(email).await;
+    let mut presets = list(email).await?;
+    let now = Local::now();
+
+    let preset = match presets.iter_mut().find(|p| p.name == name) {
+        Some(preset) => {
+            preset.options = options;
+            preset.mtime = now;
+            preset.clone()
+        }
+        None => {
+            if presets.len() >= MAX_PRESETS {
+                return Err(
+                    AppError::invalid_params(format!("at most {} presets", MAX_PRESETS)).into(),
+                );
+            }
+
+            let preset = Preset {
+                name: name.to_string(),
+                options,
+                ctime: now,
+                mtime: now,
+            };
+            presets.push(preset.clone());
+            preset
+        }
+    };
+
+    save(email, &presets).await?;
+
+    Ok(preset)
+}
+
+/// false when there is no such preset
+pub async fn remove(email: &str, name: &str) -> Result<bool> {
+    let _guard = lock(email).await;
+    let mut presets = list(email).await?;
+
+    let len = presets.len();
+    presets.retain(|p| p.name != name);
+    if presets.len() == len {
+        return Ok(false);
+    }
+
+    save(email, &presets).await?;
+
+    Ok(true)
+}
+
+async fn save(email: &str, presets: &[Preset]) -> Result<()> {
+    let body = KvReqBody::new(gen_key(email), serde_json::to_string(presets)?, None);
+    kv::insert(&body).await
+}
@@ -0,0 +1,67 @@
+use anyhow::Result;
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use super::kv::{self, KvReqBody};
+
+/// per user secret for signed /img urls
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct SigningKey {
+    pub kid: String,
+    pub secret: String,
+    pub email: String,
+    pub ctime: DateTime<Local>,
+}
+
+fn gen_key(kid: &str) -> String {
+    format!("signing_key_{}", kid)
+}
+
+/// stable per user, `u` keeps it apart from the project ids in IMG_SIGNING_KEYS
+fn gen_kid(email: &str) -> String {
+    format!(
+        "u{}",
+        &format!("{:x}", Sha256::digest(email.as_bytes()))[..16]
+    )
+}
+
+/// whether `kid` has the shape [`gen_kid`] issues, checked before any lookup
+pub fn is_user_kid(kid: &str) -> bool {
+    kid.strip_prefix('u').is_some_and(|hex| {
+        hex.len() == 16 && hex.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+    })
+}
+
+pub async fn get(kid: &str) -> Result<Option<SigningKey>> {
+    let value = kv::get(&gen_key(kid)).await?;
+
+    match value {
+        Some(value) => Ok(Some(serde_json::from_str(&value)?)),
+        None => Ok(None),
+    }
+}
+
+pub async fn get_or_insert(email: &str) -> Result<SigningKey> {
+    let kid = gen_kid(email);
+
+    if let Some(key) = get(&kid).await? {
+        return Ok(key);
+    }
+
+    let key = SigningKey {
+        kid: kid.clone(),
+        secret: format!(
+            "{}{}",
+            uuid::Uuid::new_v4().simple(),
+            uuid::Uuid::new_v4().simple()
+        ),
+        email: email.to_string(),
+        ctime: Local::now(),
+    };
+
+    let body = KvReqBody::new(gen_key(&kid), serde_json::to_string(&key)?, None);
+    kv::insert(&body).await?;
+
+    Ok(key)
+}
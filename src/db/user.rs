@@ -5,6 +5,7 @@ use super::kv::{self, KvReqBody};
 use anyhow::{self};
 use chrono::{DateTime, Local};
 use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
 
 #[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
 pub struct User {
@@ -59,7 +60,18 @@ pub async fn insert(name: &str, email: &str) -> anyhow::Result<User> {
     Ok(user)
 }
 
-pub async fn update_credits(user: &mut User, credits_delta: i64) -> anyhow::Result<()> {
+/// credit writes are read-modify-write on kv, serialized per user
+static LOCKS: [Mutex<()>; 64] = [const { Mutex::const_new(()) }; 64];
+
+/// charges against the stored balance, not a snapshot taken before the work ran
+pub async fn update_credits(email: &str, credits_delta: i64) -> anyhow::Result<()> {
+    let mut s = DefaultHasher::new();
+    email.hash(&mut s);
+    let _guard = LOCKS[s.finish() as usize % LOCKS.len()].lock().await;
+
+    let Some(mut user) = get_by_email(email).await? else {
+        return Err(anyhow::anyhow!("user {} not found", email));
+    };
     user.credit += credits_delta;
 
     let key = gen_key(&user.email).to_string();
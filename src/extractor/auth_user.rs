@@ -1,7 +1,11 @@
-use poem::{http::StatusCode, Error, FromRequest, Request, RequestBody, Result};
+use poem::{FromRequest, Request, RequestBody, Result};
 use tracing::debug;
 
-use crate::{db::user, middleware::auth::get_auth_claims};
+use crate::{
+    db::user,
+    middleware::auth::get_auth_claims,
+    status_code::{AppError, ErrorCode},
+};
 
 pub struct AuthUser {
     pub user: user::User,
@@ -13,10 +17,7 @@ impl<'a> FromRequest<'a> for AuthUser {
         let claims = get_auth_claims(req);
 
         if claims.is_none() {
-            return Err(Error::from_string(
-                "token is invalid".to_string(),
-                StatusCode::UNAUTHORIZED,
-            ));
+            return Err(AppError::new(ErrorCode::Unauthorized, "token is invalid").into());
         }
 
         let c = claims.unwrap();
@@ -29,14 +30,8 @@ impl<'a> FromRequest<'a> for AuthUser {
 
         match u {
             Ok(Some(user_model)) => Ok(AuthUser { user: user_model }),
-            Ok(None) => Err(Error::from_string(
-                "user not found".to_string(),
-                StatusCode::UNAUTHORIZED,
-            )),
-            _ => Err(Error::from_string(
-                "email invalid or db connection error".to_string(),
-                StatusCode::BAD_REQUEST,
-            )),
+            Ok(None) => Err(AppError::new(ErrorCode::Unauthorized, "user not found").into()),
+            _ => Err(AppError::internal("email invalid or db connection error").into()),
         }
     }
 }
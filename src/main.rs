@@ -3,14 +3,19 @@ mod core;
 mod db;
 mod extractor;
 mod middleware;
+mod status_code;
 
 use api::{
-    login::login,
-    resize::{resize, resize_free},
+    img::img,
+    jobs,
+    openapi::{self, API_PREFIX},
+    result_cache,
+};
+use middleware::{
+    auth::get_auth_claims, ErrorMiddleware, RateLimitMiddleware, SignedUrlMiddleware,
 };
-use middleware::{auth::get_auth_claims, AuthMiddleware};
 use poem::{
-    get, handler, listener::TcpListener, middleware::CatchPanic, post, EndpointExt, IntoResponse,
+    get, handler, listener::TcpListener, middleware::CatchPanic, EndpointExt, IntoResponse,
     Request, Result, Route, Server,
 };
 #[handler]
@@ -34,12 +39,19 @@ async fn main() -> Result<(), std::io::Error> {
     }
     tracing_subscriber::fmt::init();
 
+    jobs::start_worker().await;
+    result_cache::start_sweeper();
+
+    let api = openapi::service();
+
     let app = Route::new()
         .at("/api/hello", get(helloworld))
-        .at("/api/resizefree", post(resize_free))
-        .at("/api/resize", post(resize).with(AuthMiddleware))
-        // .at("/api/resize", post(resize))
-        .at("/api/login", get(login));
+        .at("/openapi.json", api.spec_endpoint())
+        .nest("/docs", api.swagger_ui())
+        .nest(API_PREFIX, api)
+        .at("/img/*key", get(img).with(SignedUrlMiddleware))
+        .with(RateLimitMiddleware)
+        .with(ErrorMiddleware);
     // .with(CatchPanic::new());
 
     Server::new(TcpListener::bind("0.0.0.0:3001"))
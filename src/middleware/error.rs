@@ -0,0 +1,71 @@
+use poem::{
+    http::{header, HeaderValue},
+    Endpoint, IntoResponse, Request, Response, Result,
+};
+use poem_openapi::payload::Json;
+use tracing::debug;
+
+use crate::status_code::AppError;
+
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
+/// id of the current request, from `x-request-id` when the client sent a usable one
+#[derive(Clone, Debug)]
+pub struct RequestId(pub String);
+
+impl RequestId {
+    fn from_request(req: &Request) -> Self {
+        let id = req
+            .header(REQUEST_ID_HEADER)
+            .filter(|id| {
+                !id.is_empty()
+                    && id.len() <= 64
+                    && id
+                        .bytes()
+                        .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+            })
+            .map(|id| id.to_string());
+
+        RequestId(id.unwrap_or_else(|| uuid::Uuid::new_v4().simple().to_string()))
+    }
+}
+
+pub struct ErrorRender<E>(pub E);
+
+impl<E: Endpoint> Endpoint for ErrorRender<E> {
+    type Output = Response;
+
+    async fn call(&self, mut req: Request) -> Result<Self::Output> {
+        let request_id = RequestId::from_request(&req);
+        req.extensions_mut().insert(request_id.clone());
+
+        let mut resp = match self.0.call(req).await {
+            Ok(resp) => resp.into_response(),
+            Err(err) => {
+                // handlers log the cause where they still have it
+                debug!("request {} error: {}", request_id.0, err);
+                let e = AppError::from_poem(&err);
+                let status = e.code.status();
+                // errors that pass with time say when to come back
+                let retry_after = e
+                    .details
+                    .as_ref()
+                    .and_then(|details| details["retry_after"].as_u64());
+
+                let mut resp = Json(e.into_body(request_id.0.clone())).into_response();
+                resp.set_status(status);
+                if let Some(secs) = retry_after {
+                    resp.headers_mut()
+                        .insert(header::RETRY_AFTER, HeaderValue::from(secs));
+                }
+                resp
+            }
+        };
+
+        if let Ok(value) = HeaderValue::from_str(&request_id.0) {
+            resp.headers_mut().insert(REQUEST_ID_HEADER, value);
+        }
+
+        Ok(resp)
+    }
+}
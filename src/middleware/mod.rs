@@ -1,19 +1,40 @@
 pub mod auth;
+pub mod error;
+pub mod rate_limit;
+pub mod signed_url;
 
-use poem::{http::StatusCode, Endpoint, Middleware, Response};
+use poem::{Endpoint, Middleware};
 
-pub struct AuthMiddleware;
+/// /img urls must carry a valid `kid`, `exp` and `sig`
+pub struct SignedUrlMiddleware;
 
-impl<E: Endpoint> Middleware<E> for AuthMiddleware {
-    type Output = auth::AuthorizationCheck<E>;
+impl<E: Endpoint> Middleware<E> for SignedUrlMiddleware {
+    type Output = signed_url::SignedUrlCheck<E>;
 
     fn transform(&self, ep: E) -> Self::Output {
-        auth::AuthorizationCheck(ep)
+        signed_url::SignedUrlCheck(ep)
     }
 }
 
-fn check_login_error() -> Response {
-    Response::builder()
-        .status(StatusCode::UNAUTHORIZED)
-        .finish()
+/// RATE_LIMITS per route, client ip and user, 429 with `Retry-After` over a limit,
+/// inside `ErrorMiddleware` so rejections carry the request id
+pub struct RateLimitMiddleware;
+
+impl<E: Endpoint> Middleware<E> for RateLimitMiddleware {
+    type Output = rate_limit::RateLimit<E>;
+
+    fn transform(&self, ep: E) -> Self::Output {
+        rate_limit::RateLimit(ep)
+    }
+}
+
+/// request ids, and every error rendered as an `ErrorBody`
+pub struct ErrorMiddleware;
+
+impl<E: Endpoint> Middleware<E> for ErrorMiddleware {
+    type Output = error::ErrorRender<E>;
+
+    fn transform(&self, ep: E) -> Self::Output {
+        error::ErrorRender(ep)
+    }
 }
@@ -0,0 +1,743 @@
+use std::{
+    cmp::Reverse,
+    collections::HashMap,
+    env,
+    net::{IpAddr, Ipv6Addr},
+    sync::{Arc, Mutex, OnceLock},
+    time::{Duration, SystemTime},
+};
+
+use anyhow::{Error, Result};
+use futures_util::future::BoxFuture;
+use poem::{
+    http::{HeaderMap, HeaderValue},
+    Endpoint, IntoResponse, Request, Response,
+};
+use poem_openapi::payload::Json;
+use redis::{
+    aio::{ConnectionManager, ConnectionManagerConfig},
+    Client, Script,
+};
+use serde_json::json;
+use tokio::sync::OnceCell;
+use tracing::{error, warn};
+
+use crate::{
+    middleware::{auth::get_auth_claims, error::RequestId},
+    status_code::{AppError, ErrorCode},
+};
+
+/// used when RATE_LIMITS is unset
+const DEFAULT_RULES: &str = "/api/resizefree ip 30/60; /api/resize user 60/60; \
+    /api/jobs ip 60/60; /api ip 600/60; /img ip 1200/60";
+
+/// the memory store never holds more keys than this
+const MAX_MEMORY_KEYS: usize = 100_000;
+
+/// a store slower than this lets the request through
+const REDIS_TIMEOUT: Duration = Duration::from_millis(500);
+
+static CONFIG: OnceLock<RateLimitConfig> = OnceLock::new();
+
+/// who a rule counts requests for
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Scope {
+    /// the client ip, see `client_ip`
+    Ip,
+    /// the email of a valid token, requests without one are not counted
+    User,
+}
+
+impl Scope {
+    fn name(self) -> &'static str {
+        match self {
+            Scope::Ip => "ip",
+            Scope::User => "user",
+        }
+    }
+}
+
+/// `count` requests per `period` for every path below `path`, bursts up to `count`
+#[derive(Clone, Debug)]
+pub struct Rule {
+    pub path: String,
+    pub scope: Scope,
+    pub count: u64,
+    pub period: Duration,
+}
+
+impl Rule {
+    /// `<path> <ip|user> <count>/<seconds>`, e.g. `/api/resizefree ip 30/60`
+    fn parse(s: &str) -> Result<Rule> {
+        let parts: Vec<&str> = s.split_whitespace().collect();
+        let [path, scope, limit] = parts[..] else {
+            return Err(Error::msg("expected <path> <ip|user> <count>/<seconds>"));
+        };
+
+        let scope = match scope {
+            "ip" => Scope::Ip,
+            "user" => Scope::User,
+            _ => return Err(Error::msg("scope must be ip or user")),
+        };
+
+        let (count, secs) = limit
+            .split_once('/')
+            .ok_or_else(|| Error::msg("limit must be <count>/<seconds>"))?;
+        let count: u64 = count.parse()?;
+        let secs: u64 = secs.parse()?;
+        if count == 0 || secs == 0 {
+            return Err(Error::msg("count and seconds must be greater than 0"));
+        }
+
+        Ok(Rule {
+            path: path.trim_end_matches('/').to_string(),
+            scope,
+            count,
+            period: Duration::from_secs(secs),
+        })
+    }
+
+    /// whole segments only, `/api/resize` doesn't cover `/api/resizefree`
+    fn matches(&self, path: &str) -> bool {
+        path.strip_prefix(&self.path)
+            .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+    }
+
+    /// GCRA emission interval, one request every `period / count`
+    fn interval_ms(&self) -> u64 {
+        (self.period.as_millis() as u64 / self.count).max(1)
+    }
+
+    /// `RateLimit-Policy` value
+    fn policy(&self) -> String {
+        format!("{};w={}", self.count, self.period.as_secs())
+    }
+
+    /// the bucket of `id`, the same on every instance with this rule
+    fn key(&self, id: &str) -> String {
+        format!(
+            "ratelimit:{}:{}:{}:{}",
+            self.path,
+            self.scope.name(),
+            self.policy(),
+            id
+        )
+    }
+}
+
+/// outcome of one rule for one request
+#[derive(Clone, Copy, Debug)]
+pub struct Decision {
+    pub allowed: bool,
+    /// requests left in the current burst
+    pub remaining: u64,
+    /// until the burst is full again
+    pub reset: Duration,
+    /// until the next request is allowed, zero when allowed
+    pub retry_after: Duration,
+}
+
+/// keeps the GCRA state, the theoretical arrival time of every key in unix millis
+///
+/// the memory store is per process, the redis store lets several instances count together
+pub trait Store: Send + Sync {
+    /// decide a request at `now` for every `(key, rule)`, it is only counted when all of
+    /// them allow it, atomically across the keys
+    fn check<'a>(
+        &'a self,
+        checks: &'a [(String, &'a Rule)],
+        now: u64,
+    ) -> BoxFuture<'a, Result<Vec<Decision>>>;
+}
+
+/// GCRA on a stored arrival time, returns the new one when the request is allowed
+pub fn gcra(tat: Option<u64>, rule: &Rule, now: u64) -> (Decision, Option<u64>) {
+    let interval = rule.interval_ms();
+    let period = interval * rule.count;
+
+    let tat = tat.unwrap_or(now).max(now);
+    let new_tat = tat + interval;
+    let allow_at = new_tat.saturating_sub(period);
+
+    if now < allow_at {
+        let decision = Decision {
+            allowed: false,
+            remaining: 0,
+            reset: Duration::from_millis(tat - now),
+            retry_after: Duration::from_millis(allow_at - now),
+        };
+        return (decision, None);
+    }
+
+    let decision = Decision {
+        allowed: true,
+        remaining: (period - (new_tat - now)) / interval,
+        reset: Duration::from_millis(new_tat - now),
+        retry_after: Duration::ZERO,
+    };
+    (decision, Some(new_tat))
+}
+
+#[derive(Default)]
+pub struct MemoryStore {
+    tats: Mutex<HashMap<String, u64>>,
+}
+
+impl MemoryStore {
+    /// a tat in the past is a full bucket, the same as no entry, past that the buckets
+    /// closest to full go first, a tenth at a time
+    fn make_room(tats: &mut HashMap<String, u64>, now: u64) {
+        if tats.len() < MAX_MEMORY_KEYS {
+            return;
+        }
+        tats.retain(|_, tat| *tat > now);
+        if tats.len() < MAX_MEMORY_KEYS {
+            return;
+        }
+
+        let mut all: Vec<u64> = tats.values().copied().collect();
+        let (_, &mut cut, _) = all.select_nth_unstable(MAX_MEMORY_KEYS / 10);
+        tats.retain(|_, tat| *tat > cut);
+    }
+}
+
+impl Store for MemoryStore {
+    fn check<'a>(
+        &'a self,
+        checks: &'a [(String, &'a Rule)],
+        now: u64,
+    ) -> BoxFuture<'a, Result<Vec<Decision>>> {
+        Box::pin(async move {
+            let mut tats = self.tats.lock().unwrap();
+
+            let results: Vec<(Decision, Option<u64>)> = checks
+                .iter()
+                .map(|(key, rule)| gcra(tats.get(key).copied(), rule, now))
+                .collect();
+
+            if results.iter().all(|(decision, _)| decision.allowed) {
+                for ((key, _), (_, new_tat)) in checks.iter().zip(&results) {
+                    if let Some(new_tat) = new_tat {
+                        if !tats.contains_key(key) {
+                            Self::make_room(&mut tats, now);
+                        }
+                        tats.insert(key.clone(), *new_tat);
+                    }
+                }
+            }
+
+            Ok(results.into_iter().map(|(decision, _)| decision).collect())
+        })
+    }
+}
+
+/// `gcra` over every key, the new arrival times are only written when all of them allow
+/// the request, returns the arrival time each key had
+///
+/// ARGV is `now` then the interval and period of every key, in millis
+const REDIS_SCRIPT: &str = r#"
+local now = tonumber(ARGV[1])
+local tats = {}
+local allowed = true
+for i, key in ipairs(KEYS) do
+    local interval = tonumber(ARGV[i * 2])
+    local period = tonumber(ARGV[i * 2 + 1])
+    local tat = math.max(tonumber(redis.call('GET', key) or now), now)
+    tats[i] = tat
+    if now < tat + interval - period then
+        allowed = false
+    end
+end
+if allowed then
+    for i, key in ipairs(KEYS) do
+        local new_tat = tats[i] + tonumber(ARGV[i * 2])
+        redis.call('SET', key, string.format('%d', new_tat), 'PX', new_tat - now)
+    end
+end
+return tats
+"#;
+
+/// buckets in redis, shared by every instance on it, keys expire once their bucket is full
+pub struct RedisStore {
+    client: Client,
+    conn: OnceCell<ConnectionManager>,
+    script: Script,
+}
+
+impl RedisStore {
+    pub fn new(url: &str) -> Result<RedisStore> {
+        Ok(RedisStore {
+            client: Client::open(url)?,
+            conn: OnceCell::new(),
+            script: Script::new(REDIS_SCRIPT),
+        })
+    }
+
+    /// connects on first use and reconnects by itself after that
+    async fn conn(&self) -> Result<ConnectionManager> {
+        let conn = self
+            .conn
+            .get_or_try_init(|| {
+                let config = ConnectionManagerConfig::new()
+                    .set_number_of_retries(0)
+                    .set_connection_timeout(REDIS_TIMEOUT)
+                    .set_response_timeout(REDIS_TIMEOUT);
+                ConnectionManager::new_with_config(self.client.clone(), config)
+            })
+            .await?;
+
+        Ok(conn.clone())
+    }
+}
+
+impl Store for RedisStore {
+    fn check<'a>(
+        &'a self,
+        checks: &'a [(String, &'a Rule)],
+        now: u64,
+    ) -> BoxFuture<'a, Result<Vec<Decision>>> {
+        Box::pin(async move {
+            let mut conn = self.conn().await?;
+
+            let mut invocation = self.script.prepare_invoke();
+            invocation.arg(now);
+            for (key, rule) in checks {
+                let interval = rule.interval_ms();
+                invocation.key(key).arg(interval).arg(interval * rule.count);
+            }
+            let tats: Vec<u64> = invocation.invoke_async(&mut conn).await?;
+            if tats.len() != checks.len() {
+                return Err(Error::msg(
+                    "rate limit script returned the wrong number of keys",
+                ));
+            }
+
+            // the same arrival times give the decisions the script made
+            Ok(checks
+                .iter()
+                .zip(tats)
+                .map(|((_, rule), tat)| gcra(Some(tat), rule, now).0)
+                .collect())
+        })
+    }
+}
+
+/// an ip or a network in CIDR notation
+#[derive(Clone, Copy, Debug)]
+struct Network {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl Network {
+    fn parse(s: &str) -> Result<Network> {
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr.parse::<IpAddr>()?, Some(prefix.parse::<u8>()?)),
+            None => (s.parse::<IpAddr>()?, None),
+        };
+
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = prefix.unwrap_or(max);
+        if prefix > max {
+            return Err(Error::msg("prefix is too long"));
+        }
+
+        Ok(Network { addr, prefix })
+    }
+
+    fn contains(&self, ip: IpAddr) -> bool {
+        let ip = ip.to_canonical();
+        match (self.addr, ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+struct RateLimitConfig {
+    rules: Vec<Rule>,
+    trusted_proxies: Vec<Network>,
+    store: Arc<dyn Store>,
+}
+
+/// RATE_LIMITS, rules separated by `;` (see `Rule::parse`), empty turns limiting off,
+/// every matching rule must allow a request
+///
+/// TRUSTED_PROXIES, ips or CIDRs separated by commas, X-Forwarded-For is only read
+/// when the peer is one of them
+///
+/// RATE_LIMIT_REDIS_URL, counts in redis instead of per process when set
+fn get_config() -> &'static RateLimitConfig {
+    CONFIG.get_or_init(|| {
+        let rules = env::var("RATE_LIMITS")
+            .unwrap_or_else(|_| DEFAULT_RULES.to_string())
+            .split(';')
+            .map(str::trim)
+            .filter(|s| !s.is_empty())
+            .filter_map(|s| match Rule::parse(s) {
+                Ok(rule) => Some(rule),
+                Err(e) => {
+                    warn!("RATE_LIMITS rule {} is ignored: {}", s, e);
+                    None
+                }
+            })
+            .collect();
+
+        let trusted_proxies = env::var("TRUSTED_PROXIES")
+            .unwrap_or_default()
+            .split(',')
+            .map(str::trim)
+            .filter(|s| !s.is_empty())
+            .filter_map(|s| match Network::parse(s) {
+                Ok(network) => Some(network),
+                Err(e) => {
+                    warn!("TRUSTED_PROXIES entry {} is ignored: {}", s, e);
+                    None
+                }
+            })
+            .collect();
+
+        let store: Arc<dyn Store> = match env::var("RATE_LIMIT_REDIS_URL") {
+            Ok(url) if !url.is_empty() => match RedisStore::new(&url) {
+                Ok(store) => Arc::new(store),
+                Err(e) => {
+                    warn!("RATE_LIMIT_REDIS_URL is ignored: {}", e);
+                    Arc::new(MemoryStore::default())
+                }
+            },
+            _ => Arc::new(MemoryStore::default()),
+        };
+
+        RateLimitConfig {
+            rules,
+            trusted_proxies,
+            store,
+        }
+    })
+}
+
+/// the peer, or the last X-Forwarded-For hop that is not a trusted proxy when the peer is one
+fn client_ip(peer: IpAddr, headers: &HeaderMap, trusted: &[Network]) -> IpAddr {
+    let peer = peer.to_canonical();
+
+    let is_trusted = |ip: IpAddr| trusted.iter().any(|n| n.contains(ip));
+    if !is_trusted(peer) {
+        return peer;
+    }
+
+    let forwarded: Vec<IpAddr> = headers
+        .get_all("x-forwarded-for")
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .filter_map(|hop| hop.trim().parse::<IpAddr>().ok())
+        .map(|ip| ip.to_canonical())
+        .collect();
+
+    // hops are appended, the rightmost untrusted one is the first we can't vouch for
+    forwarded
+        .into_iter()
+        .rev()
+        .find(|&ip| !is_trusted(ip))
+        .unwrap_or(peer)
+}
+
+/// one bucket per ipv6 /64, a single host usually holds a whole one
+fn client_id(ip: IpAddr) -> String {
+    match ip {
+        IpAddr::V4(ip) => ip.to_string(),
+        IpAddr::V6(ip) => {
+            let network = Ipv6Addr::from(u128::from(ip) & !(u64::MAX as u128));
+            format!("{}/64", network)
+        }
+    }
+}
+
+fn set_header(resp: &mut Response, name: &'static str, value: String) {
+    if let Ok(value) = HeaderValue::from_str(&value) {
+        resp.headers_mut().insert(name, value);
+    }
+}
+
+/// whole seconds, rounded up so a client waiting that long is never too early
+fn secs(d: Duration) -> u64 {
+    d.as_millis().div_ceil(1000) as u64
+}
+
+pub struct RateLimit<E>(pub E);
+
+impl<E: Endpoint> Endpoint for RateLimit<E> {
+    type Output = Response;
+
+    async fn call(&self, req: Request) -> poem::Result<Self::Output> {
+        let config = get_config();
+        let path = req.uri().path().to_string();
+
+        let rules: Vec<&Rule> = config
+            .rules
+            .iter()
+            .filter(|rule| rule.matches(&path))
+            .collect();
+        if rules.is_empty() {
+            return self.0.call(req).await.map(IntoResponse::into_response);
+        }
+
+        let ip = req
+            .remote_addr()
+            .as_socket_addr()
+            .map(|addr| client_id(client_ip(addr.ip(), req.headers(), &config.trusted_proxies)));
+        let user = get_auth_claims(&req).map(|claims| claims.email);
+
+        let checks: Vec<(String, &Rule)> = rules
+            .into_iter()
+            .filter_map(|rule| {
+                let id = match rule.scope {
+                    Scope::Ip => ip.as_deref(),
+                    Scope::User => user.as_deref(),
+                }?;
+                Some((rule.key(id), rule))
+            })
+            .collect();
+        if checks.is_empty() {
+            return self.0.call(req).await.map(IntoResponse::into_response);
+        }
+
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64;
+
+        let decisions = match config.store.check(&checks, now).await {
+            Ok(decisions) => decisions,
+            Err(e) => {
+                // a broken shared store shouldn't take the api down
+                error!("rate limit store error: {:?}", e);
+                return self.0.call(req).await.map(IntoResponse::into_response);
+            }
+        };
+
+        // the tightest rule is the one reported in the headers, a rejecting one first
+        let Some((rule, decision)) = checks
+            .iter()
+            .map(|(_, rule)| *rule)
+            .zip(decisions)
+            .min_by_key(|(_, d)| (d.allowed, d.remaining, Reverse(d.retry_after)))
+        else {
+            return self.0.call(req).await.map(IntoResponse::into_response);
+        };
+
+        let mut resp = if decision.allowed {
+            self.0.call(req).await?.into_response()
+        } else {
+            let request_id = req
+                .extensions()
+                .get::<RequestId>()
+                .map(|id| id.0.clone())
+                .unwrap_or_default();
+            let e = AppError::new(ErrorCode::TooManyRequests, "too many requests").with_details(
+                json!({ "retry_after": secs(decision.retry_after), "limit": rule.count }),
+            );
+
+            let mut resp = Json(e.into_body(request_id)).into_response();
+            resp.set_status(ErrorCode::TooManyRequests.status());
+            set_header(
+                &mut resp,
+                "retry-after",
+                secs(decision.retry_after).to_string(),
+            );
+            resp
+        };
+
+        set_header(&mut resp, "ratelimit-limit", rule.count.to_string());
+        set_header(
+            &mut resp,
+            "ratelimit-remaining",
+            decision.remaining.to_string(),
+        );
+        set_header(
+            &mut resp,
+            "ratelimit-reset",
+            secs(decision.reset).to_string(),
+        );
+        set_header(&mut resp, "ratelimit-policy", rule.policy());
+
+        Ok(resp)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(s: &str) -> Rule {
+        Rule::parse(s).unwrap()
+    }
+
+    fn headers(forwarded: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert("x-forwarded-for", HeaderValue::from_str(forwarded).unwrap());
+        headers
+    }
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn parse_rules() {
+        let r = rule("/api/resize/ user 60/30");
+        assert_eq!(r.path, "/api/resize");
+        assert_eq!(r.scope, Scope::User);
+        assert_eq!(r.count, 60);
+        assert_eq!(r.period, Duration::from_secs(30));
+        assert_eq!(r.interval_ms(), 500);
+        assert_eq!(r.policy(), "60;w=30");
+
+        for bad in [
+            "/api ip",
+            "/api ip 10/60 extra",
+            "/api host 10/60",
+            "/api ip 10",
+            "/api ip 0/60",
+            "/api ip 10/0",
+            "/api ip ten/60",
+        ] {
+            assert!(Rule::parse(bad).is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn rules_match_whole_segments() {
+        let r = rule("/api/resize ip 1/1");
+        assert!(r.matches("/api/resize"));
+        assert!(r.matches("/api/resize/x"));
+        assert!(!r.matches("/api/resizefree"));
+        assert!(!r.matches("/api"));
+    }
+
+    #[test]
+    fn gcra_allows_a_burst_then_one_per_interval() {
+        let r = rule("/api ip 3/3");
+        let mut tat = None;
+
+        for remaining in [2, 1, 0] {
+            let (decision, new_tat) = gcra(tat, &r, 0);
+            assert!(decision.allowed);
+            assert_eq!(decision.remaining, remaining);
+            tat = new_tat;
+        }
+        assert_eq!(tat, Some(3000));
+
+        let (decision, new_tat) = gcra(tat, &r, 0);
+        assert!(!decision.allowed);
+        assert_eq!(new_tat, None);
+        assert_eq!(decision.retry_after, Duration::from_millis(1000));
+        assert_eq!(decision.reset, Duration::from_millis(3000));
+
+        let (decision, new_tat) = gcra(tat, &r, 1000);
+        assert!(decision.allowed);
+        assert_eq!(decision.remaining, 0);
+        assert_eq!(new_tat, Some(4000));
+
+        // an old arrival time is a full bucket
+        let (decision, _) = gcra(Some(10), &r, 60_000);
+        assert!(decision.allowed);
+        assert_eq!(decision.remaining, 2);
+    }
+
+    #[tokio::test]
+    async fn rejected_requests_consume_no_rule() {
+        let store = MemoryStore::default();
+        let wide = rule("/api ip 10/60");
+        let narrow = rule("/api/resize ip 1/60");
+        let checks = vec![("wide".to_string(), &wide), ("narrow".to_string(), &narrow)];
+
+        let decisions = store.check(&checks, 0).await.unwrap();
+        assert!(decisions.iter().all(|d| d.allowed));
+        assert_eq!(decisions[0].remaining, 9);
+
+        for _ in 0..3 {
+            let decisions = store.check(&checks, 0).await.unwrap();
+            assert!(!decisions[1].allowed);
+        }
+
+        let decisions = store.check(&checks[..1], 0).await.unwrap();
+        assert!(decisions[0].allowed);
+        assert_eq!(decisions[0].remaining, 8);
+    }
+
+    #[test]
+    fn memory_store_is_capped() {
+        let mut tats: HashMap<String, u64> = (0..MAX_MEMORY_KEYS as u64)
+            .map(|i| (i.to_string(), 1000 + i))
+            .collect();
+
+        MemoryStore::make_room(&mut tats, 0);
+        assert!(tats.len() < MAX_MEMORY_KEYS);
+        // the buckets closest to full went first
+        assert!(!tats.contains_key("0"));
+        assert!(tats.contains_key(&(MAX_MEMORY_KEYS - 1).to_string()));
+    }
+
+    #[test]
+    fn client_ip_trusts_only_listed_proxies() {
+        let trusted = [Network::parse("10.0.0.0/8").unwrap()];
+
+        // an untrusted peer can't claim another address
+        assert_eq!(
+            client_ip(ip("203.0.113.9"), &headers("198.51.100.1"), &trusted),
+            ip("203.0.113.9")
+        );
+
+        // the rightmost hop that isn't a proxy
+        assert_eq!(
+            client_ip(
+                ip("10.0.0.2"),
+                &headers("1.2.3.4, 198.51.100.1, 10.0.0.1"),
+                &trusted
+            ),
+            ip("198.51.100.1")
+        );
+
+        // only proxies, or nothing usable, is the peer
+        assert_eq!(
+            client_ip(ip("10.0.0.2"), &headers("10.0.0.1, junk"), &trusted),
+            ip("10.0.0.2")
+        );
+        assert_eq!(
+            client_ip(ip("10.0.0.2"), &HeaderMap::new(), &trusted),
+            ip("10.0.0.2")
+        );
+
+        // mapped addresses are the ipv4 they carry
+        assert_eq!(
+            client_ip(
+                ip("::ffff:10.0.0.2"),
+                &headers("::ffff:198.51.100.1"),
+                &trusted
+            ),
+            ip("198.51.100.1")
+        );
+    }
+
+    #[test]
+    fn ipv6_clients_share_a_64() {
+        assert_eq!(client_id(ip("198.51.100.1")), "198.51.100.1");
+        assert_eq!(
+            client_id(ip("2001:db8:1:2:aaaa::1")),
+            client_id(ip("2001:db8:1:2:bbbb::2"))
+        );
+        assert_eq!(client_id(ip("2001:db8:1:2::1")), "2001:db8:1:2::/64");
+        assert_ne!(
+            client_id(ip("2001:db8:1:2::1")),
+            client_id(ip("2001:db8:1:3::1"))
+        );
+    }
+}
@@ -0,0 +1,163 @@
+use std::{
+    collections::HashMap,
+    env,
+    sync::{Mutex, OnceLock},
+    time::{Duration, Instant, SystemTime},
+};
+
+use anyhow::{Error, Result};
+use poem::{Endpoint, IntoResponse, Request, Response};
+use tracing::{debug, error};
+use url::form_urlencoded;
+
+use crate::{
+    core::signed_url,
+    db::signing_key,
+    status_code::{AppError, ErrorCode},
+};
+
+/// how long a kv signing key is trusted before it is read again
+const KEY_CACHE_TTL: Duration = Duration::from_secs(5 * 60);
+
+/// how long a kid missing from kv is refused without asking again, short so a key
+/// issued meanwhile is usable soon
+const MISSING_KEY_TTL: Duration = Duration::from_secs(30);
+
+/// user keys kept at once, the oldest goes first
+const MAX_CACHED_KEYS: usize = 10_000;
+
+static PROJECT_KEYS: OnceLock<HashMap<String, String>> = OnceLock::new();
+static REQUIRE_SIGNATURE: OnceLock<bool> = OnceLock::new();
+/// kid to the secret read at that time, `None` for a kid kv doesn't have
+type KeyCache = Mutex<HashMap<String, (Instant, Option<String>)>>;
+
+static KEY_CACHE: OnceLock<KeyCache> = OnceLock::new();
+
+/// IMG_SIGNING_KEYS, `kid:secret` pairs separated by commas
+fn get_project_keys() -> &'static HashMap<String, String> {
+    PROJECT_KEYS.get_or_init(|| {
+        env::var("IMG_SIGNING_KEYS")
+            .unwrap_or_default()
+            .split(',')
+            .filter_map(|pair| pair.trim().split_once(':'))
+            .map(|(kid, secret)| (kid.to_string(), secret.to_string()))
+            .collect()
+    })
+}
+
+/// IMG_REQUIRE_SIGNATURE=false turns the check off, e.g. for local development
+fn require_signature() -> bool {
+    *REQUIRE_SIGNATURE.get_or_init(|| {
+        env::var("IMG_REQUIRE_SIGNATURE").map_or(true, |v| v != "false" && v != "0")
+    })
+}
+
+fn get_key_cache() -> &'static KeyCache {
+    KEY_CACHE.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+fn is_fresh(time: &Instant, secret: &Option<String>) -> bool {
+    let ttl = match secret {
+        Some(_) => KEY_CACHE_TTL,
+        None => MISSING_KEY_TTL,
+    };
+    time.elapsed() < ttl
+}
+
+/// project keys first, then user keys from the kv store, a kid that can't have been
+/// issued costs no lookup
+async fn find_secret(kid: &str) -> Result<Option<String>> {
+    if let Some(secret) = get_project_keys().get(kid) {
+        return Ok(Some(secret.clone()));
+    }
+
+    if !signing_key::is_user_kid(kid) {
+        return Ok(None);
+    }
+
+    if let Some((time, secret)) = get_key_cache().lock().unwrap().get(kid) {
+        if is_fresh(time, secret) {
+            return Ok(secret.clone());
+        }
+    }
+
+    // a miss is cached too, otherwise every made up kid is a kv round trip
+    let secret = signing_key::get(kid).await?.map(|key| key.secret);
+
+    let mut cache = get_key_cache().lock().unwrap();
+    if cache.len() >= MAX_CACHED_KEYS {
+        cache.retain(|_, (time, secret)| is_fresh(time, secret));
+    }
+    if cache.len() >= MAX_CACHED_KEYS {
+        let oldest = cache
+            .iter()
+            .min_by_key(|(_, (time, _))| *time)
+            .map(|(kid, _)| kid.clone());
+        if let Some(oldest) = oldest {
+            cache.remove(&oldest);
+        }
+    }
+    cache.insert(kid.to_string(), (Instant::now(), secret.clone()));
+
+    Ok(secret)
+}
+
+async fn check(req: &Request) -> Result<()> {
+    let params: Vec<(String, String)> =
+        form_urlencoded::parse(req.uri().query().unwrap_or("").as_bytes())
+            .into_owned()
+            .collect();
+    let param = |name: &str| {
+        params
+            .iter()
+            .find(|(k, _)| k == name)
+            .map(|(_, v)| v.as_str())
+    };
+
+    let sig = param("sig").ok_or_else(|| Error::msg("url is not signed"))?;
+    let kid = param("kid").ok_or_else(|| Error::msg("url has no kid"))?;
+    let exp = param("exp")
+        .and_then(|exp| exp.parse::<u64>().ok())
+        .ok_or_else(|| Error::msg("url has no exp"))?;
+
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)?
+        .as_secs();
+    if exp < now {
+        return Err(Error::msg("url is expired"));
+    }
+
+    let secret = find_secret(kid)
+        .await?
+        .ok_or_else(|| Error::msg("kid is unknown"))?;
+
+    let canonical = signed_url::canonical(req.uri().path(), &params);
+    if !signed_url::verify(&secret, &canonical, sig)? {
+        return Err(Error::msg("sig does not match"));
+    }
+
+    Ok(())
+}
+
+pub struct SignedUrlCheck<E>(pub E);
+
+impl<E: Endpoint> Endpoint for SignedUrlCheck<E> {
+    type Output = Response;
+
+    async fn call(&self, req: Request) -> poem::Result<Self::Output> {
+        if require_signature() {
+            if let Err(e) = check(&req).await {
+                debug!("signed url rejected: {}", e);
+                return Err(AppError::new(ErrorCode::Forbidden, e.to_string()).into());
+            }
+        }
+
+        match self.0.call(req).await {
+            Ok(resp) => Ok(resp.into_response()),
+            Err(err) => {
+                error!("error: {err}");
+                Err(err)
+            }
+        }
+    }
+}
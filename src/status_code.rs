@@ -0,0 +1,257 @@
+use std::fmt::{self, Display};
+
+use image::ImageError;
+use poem::{error::ResponseError, http::StatusCode, IntoResponse, Response};
+use poem_openapi::{
+    payload::{Json, Payload},
+    registry::{MetaMediaType, MetaResponse, MetaResponses, Registry},
+    types::Type,
+    ApiResponse, Enum, Object,
+};
+use serde::Serialize;
+use serde_json::{json, Value};
+use tracing::error;
+
+/// stable codes for clients to branch on, messages may change
+#[derive(Enum, Clone, Copy, Debug, PartialEq, Eq)]
+#[oai(rename_all = "snake_case")]
+pub enum ErrorCode {
+    /// a param is missing, malformed or out of range
+    InvalidParams,
+    /// the image can't be decoded
+    InvalidImage,
+    /// no token, or an invalid or expired one
+    Unauthorized,
+    /// not enough credits for the ai sizes
+    InsufficientCredits,
+    /// e.g. an unsigned or expired /img url
+    Forbidden,
+    NotFound,
+    MethodNotAllowed,
+    /// e.g. the result of a job that is not done yet
+    Conflict,
+    /// an upload, zip or source_url over its limit
+    PayloadTooLarge,
+    /// an upload in a format that is not supported
+    UnsupportedFormat,
+    /// over a rate limit, `Retry-After` says when to try again
+    TooManyRequests,
+    /// too much image work queued, try again later
+    Overloaded,
+    /// the object store, replicate or another service failed
+    Upstream,
+    Internal,
+}
+
+impl ErrorCode {
+    pub fn status(self) -> StatusCode {
+        match self {
+            ErrorCode::InvalidParams | ErrorCode::InvalidImage => StatusCode::BAD_REQUEST,
+            ErrorCode::Unauthorized => StatusCode::UNAUTHORIZED,
+            ErrorCode::InsufficientCredits => StatusCode::PAYMENT_REQUIRED,
+            ErrorCode::Forbidden => StatusCode::FORBIDDEN,
+            ErrorCode::NotFound => StatusCode::NOT_FOUND,
+            ErrorCode::MethodNotAllowed => StatusCode::METHOD_NOT_ALLOWED,
+            ErrorCode::Conflict => StatusCode::CONFLICT,
+            ErrorCode::PayloadTooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+            ErrorCode::UnsupportedFormat => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            ErrorCode::TooManyRequests => StatusCode::TOO_MANY_REQUESTS,
+            ErrorCode::Overloaded => StatusCode::SERVICE_UNAVAILABLE,
+            ErrorCode::Upstream => StatusCode::BAD_GATEWAY,
+            ErrorCode::Internal => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    /// statuses without a code of their own fall back to invalid_params or internal
+    pub fn from_status(status: StatusCode) -> Self {
+        match status {
+            StatusCode::UNAUTHORIZED => ErrorCode::Unauthorized,
+            StatusCode::PAYMENT_REQUIRED => ErrorCode::InsufficientCredits,
+            StatusCode::FORBIDDEN => ErrorCode::Forbidden,
+            StatusCode::NOT_FOUND => ErrorCode::NotFound,
+            StatusCode::METHOD_NOT_ALLOWED => ErrorCode::MethodNotAllowed,
+            StatusCode::CONFLICT => ErrorCode::Conflict,
+            StatusCode::PAYLOAD_TOO_LARGE => ErrorCode::PayloadTooLarge,
+            StatusCode::UNSUPPORTED_MEDIA_TYPE => ErrorCode::UnsupportedFormat,
+            StatusCode::TOO_MANY_REQUESTS => ErrorCode::TooManyRequests,
+            StatusCode::BAD_GATEWAY => ErrorCode::Upstream,
+            StatusCode::SERVICE_UNAVAILABLE => ErrorCode::Overloaded,
+            s if s.is_client_error() => ErrorCode::InvalidParams,
+            _ => ErrorCode::Internal,
+        }
+    }
+}
+
+/// body of every error response
+#[derive(Object)]
+pub struct ErrorBody {
+    pub code: ErrorCode,
+    pub message: String,
+    /// e.g. the failing fields or the credits needed
+    pub details: Option<Value>,
+    /// also sent as `x-request-id`, quote it when reporting a problem
+    pub request_id: String,
+}
+
+/// one failed check of an invalid_params error, listed in `details.fields`
+#[derive(Serialize, Debug)]
+pub struct FieldError {
+    /// e.g. `sizes[1].scale`
+    pub field: String,
+    pub message: String,
+}
+
+/// error of a handler or middleware, rendered as an `ErrorBody` by `ErrorMiddleware`
+#[derive(Clone, Debug)]
+pub struct AppError {
+    pub code: ErrorCode,
+    pub message: String,
+    pub details: Option<Value>,
+}
+
+impl AppError {
+    pub fn new(code: ErrorCode, message: impl Into<String>) -> Self {
+        AppError {
+            code,
+            message: message.into(),
+            details: None,
+        }
+    }
+
+    pub fn with_details(mut self, details: Value) -> Self {
+        self.details = Some(details);
+        self
+    }
+
+    pub fn invalid_params(message: impl Into<String>) -> Self {
+        AppError::new(ErrorCode::InvalidParams, message)
+    }
+
+    /// every failed check at once, the message lists them too
+    pub fn invalid_fields(fields: Vec<FieldError>) -> Self {
+        let message = fields
+            .iter()
+            .map(|f| format!("{}: {}", f.field, f.message))
+            .collect::<Vec<_>>()
+            .join("; ");
+
+        AppError::invalid_params(message).with_details(json!({ "fields": fields }))
+    }
+
+    pub fn internal(message: impl Into<String>) -> Self {
+        AppError::new(ErrorCode::Internal, message)
+    }
+
+    /// an `AppError` anywhere in the chain is kept, poem and image errors get their own
+    /// code and anything else becomes `code`
+    pub fn from_anyhow(e: &anyhow::Error, code: ErrorCode) -> Self {
+        // `context(AppError)` is only found this way
+        if let Some(e) = e.downcast_ref::<AppError>() {
+            return e.clone();
+        }
+
+        for cause in e.chain() {
+            if let Some(e) = cause.downcast_ref::<AppError>() {
+                return e.clone();
+            }
+
+            // extractor errors, e.g. a malformed form or json body
+            if let Some(e) = cause.downcast_ref::<poem::Error>() {
+                return AppError::from_poem(e);
+            }
+
+            if let Some(e @ image_resize_core::Error::UnsupportedFormat(_)) = cause.downcast_ref() {
+                return AppError::new(ErrorCode::UnsupportedFormat, e.to_string());
+            }
+
+            if let Some(e) = cause.downcast_ref::<ImageError>() {
+                let code = match e {
+                    ImageError::Unsupported(_) => ErrorCode::UnsupportedFormat,
+                    ImageError::Limits(_) => ErrorCode::PayloadTooLarge,
+                    ImageError::Decoding(_) => ErrorCode::InvalidImage,
+                    _ => continue,
+                };
+                return AppError::new(code, e.to_string());
+            }
+        }
+
+        AppError::new(code, format!("{:#}", e))
+    }
+
+    /// errors of extractors, routing and auth keep their status
+    pub fn from_poem(e: &poem::Error) -> Self {
+        if let Some(e) = e.downcast_ref::<AppError>() {
+            return e.clone();
+        }
+
+        AppError::new(ErrorCode::from_status(e.status()), e.to_string())
+    }
+
+    pub fn into_body(self, request_id: String) -> ErrorBody {
+        ErrorBody {
+            code: self.code,
+            message: self.message,
+            details: self.details,
+            request_id,
+        }
+    }
+}
+
+impl Display for AppError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl std::error::Error for AppError {}
+
+impl ResponseError for AppError {
+    fn status(&self) -> StatusCode {
+        self.code.status()
+    }
+
+    /// only without `ErrorMiddleware`, which has the request id
+    fn as_response(&self) -> Response {
+        let mut resp = Json(self.clone().into_body(String::new())).into_response();
+        resp.set_status(self.status());
+        resp
+    }
+}
+
+/// typed endpoints return `Result<_, AppError>`, every one documents it as the default response
+impl ApiResponse for AppError {
+    fn meta() -> MetaResponses {
+        MetaResponses {
+            responses: vec![MetaResponse {
+                description: "an error, `code` tells which",
+                status: None,
+                status_range: None,
+                content: vec![MetaMediaType {
+                    content_type: Json::<ErrorBody>::CONTENT_TYPE,
+                    schema: ErrorBody::schema_ref(),
+                }],
+                headers: vec![],
+            }],
+        }
+    }
+
+    fn register(registry: &mut Registry) {
+        ErrorBody::register(registry);
+    }
+}
+
+pub type AppResult<T> = Result<T, AppError>;
+
+pub trait ResultExt<T> {
+    /// log the cause and keep its code, `code` when it has none
+    fn app_err(self, code: ErrorCode) -> AppResult<T>;
+}
+
+impl<T> ResultExt<T> for anyhow::Result<T> {
+    fn app_err(self, code: ErrorCode) -> AppResult<T> {
+        self.map_err(|e| {
+            error!("{:?}", e);
+            AppError::from_anyhow(&e, code)
+        })
+    }
+}